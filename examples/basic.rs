@@ -5,7 +5,7 @@ use hyper::{Body, Method, Request};
 use mime;
 use mime::Mime;
 
-use airship::resource::{Resource, Webmachine};
+use airship::resource::{BodyProducer, Resource, Webmachine};
 use airship::server;
 use airship::types::{HasAirshipState, RequestState};
 use webmachine_derive::*;
@@ -24,12 +24,20 @@ impl Webmachine for GetResource {
     fn content_types_provided<S: HasAirshipState>(
         &self,
         _state: &mut S,
-    ) -> Vec<(Mime, fn(&Request) -> Body)> {
+    ) -> Vec<(Mime, BodyProducer<S>)> {
         vec![
-            (mime::TEXT_PLAIN, |_x: &Request| Body::from("ok")),
-            (mime::APPLICATION_JSON, |_x: &Request| {
-                Body::from("{\"key\": \"value\"}")
-            }),
+            (
+                mime::TEXT_PLAIN,
+                Box::new(|_state: &mut S, _req: &Request| {
+                    Ok(Body::from("ok").into())
+                }),
+            ),
+            (
+                mime::APPLICATION_JSON,
+                Box::new(|_state: &mut S, _req: &Request| {
+                    Ok(Body::from("{\"key\": \"value\"}").into())
+                }),
+            ),
         ]
     }
 
@@ -4,142 +4,234 @@
 
 #![allow(clippy::type_complexity)]
 
-use futures::Future;
+use std::cmp::Reverse;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::{Future, Stream};
 use hyper::header::*;
-use hyper::{Body, Method, Request, Response, StatusCode};
-use itertools::Itertools;
+use hyper::{Body, Chunk, Method, Request, Response, StatusCode, Uri};
 use mime::Mime;
+use percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+use unicase::Ascii;
 
-use crate::resource::{PostResponse, Webmachine};
+use crate::encoding;
+use crate::encoding::EncodedBody;
+use crate::error::Error;
+use crate::resource::{
+    BodyAcceptor, CharsetConverter, PostResponse, ProvidedBody, RangeProducer,
+    Webmachine,
+};
 use crate::types::*;
+use crate::upgrade::is_upgrade_request;
 
 header! { (AirshipTrace, "Airship-Trace") => [String] }
 header! { (AirshipQuip, "Airship-Quip") => [String] }
 
-type BoxedFuture = Box<dyn Future<Item = Response, Error = hyper::Error>>;
+type BoxedFuture =
+    Box<dyn Future<Item = Response<ResponseBody>, Error = hyper::Error>>;
 
-pub fn traverse<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+pub fn traverse<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
+    set_request_accept(&mut state, req.headers().get::<Accept>().cloned());
     b13(r, req, state)
 }
 
-fn halt<S: HasAirshipState>(
+pub(crate) fn halt<S: HasAirshipState>(
     status_code: StatusCode,
-    state: &mut S,
+    mut state: S,
 ) -> BoxedFuture {
-    let trace = get_trace(state).join(",");
+    let trace = get_trace(&state).join(",");
     let quip = String::from("blame me if inappropriate");
 
+    let response = get_response(&mut state)
+        .with_status(status_code)
+        .with_header(Server::new("hyper/0.11.27"))
+        .with_header(AirshipTrace(trace))
+        .with_header(AirshipQuip(quip));
+
+    let response = with_error_response(response, status_code, &state);
+    let response = with_variances(response, status_code, &state);
+    let headers = response.headers().clone();
+    let body: ResponseBody = match take_response_stream(&mut state) {
+        Some(stream) => stream,
+        None => Box::new(response.body()),
+    };
+    let body = wrap_response_body(&mut state, body);
     Box::new(futures::future::ok(
         Response::new()
             .with_status(status_code)
-            .with_header(Server::new("hyper/0.11.27"))
-            .with_header(AirshipTrace(trace))
-            .with_header(AirshipQuip(quip)),
+            .with_headers(headers)
+            .with_body(body),
     ))
 }
 
-fn halt_with_response<S: HasAirshipState>(
-    status_code: StatusCode,
-    state: &mut S,
+/// Halts with the status of an error returned by a callback. The error's
+/// headers are added to the response, and its bodies are sent in place of the
+/// error responses registered for the status.
+fn halt_with_error<S: HasAirshipState>(
+    error: Error,
+    mut state: S,
 ) -> BoxedFuture {
-    let trace = get_trace(state).join(",");
-    let quip = String::from("blame me if inappropriate");
-
-    let response = get_response(state)
-        .with_status(status_code)
-        .with_header(Server::new("hyper/0.11.27"))
-        .with_header(AirshipTrace(trace))
-        .with_header(AirshipQuip(quip));
+    let Error {
+        status,
+        responses,
+        headers,
+        ..
+    } = error;
+    set_response_headers(&mut state, &headers);
+    if !responses.is_empty() {
+        set_error_response(&mut state, status, responses);
+    }
+    halt(status, state)
+}
 
-    Box::new(futures::future::ok(response))
+/// Continues with `next` once an asynchronous callback resolves, or halts
+/// with the error the callback failed with.
+fn continue_with<S, T, F>(callback: StateFuture<S, T>, next: F) -> BoxedFuture
+where
+    S: HasAirshipState + 'static,
+    T: 'static,
+    F: FnOnce(S, T) -> BoxedFuture + 'static,
+{
+    Box::new(callback.then(move |result| match result {
+        Ok((state, value)) => next(state, value),
+        Err((state, error)) => halt_with_error(error, state),
+    }))
 }
 
 fn halt_with_header<H: Header, S: HasAirshipState>(
     status_code: StatusCode,
     hdr: H,
-    state: &mut S,
+    mut state: S,
 ) -> BoxedFuture {
-    let trace = get_trace(state).join(",");
-    let quip = String::from("blame me if inappropriate");
+    set_response_header(&mut state, hdr);
+    halt(status_code, state)
+}
 
-    Box::new(futures::future::ok(
-        Response::new()
-            .with_status(status_code)
-            .with_header(hdr)
-            .with_header(Server::new("hyper/0.11.27"))
-            .with_header(AirshipTrace(trace))
-            .with_header(AirshipQuip(quip)),
-    ))
+/// Adds the request headers that the representation varies by as a `Vary`
+/// header. It is only sent with a successful or `304 Not Modified` response,
+/// since the others do not carry the negotiated representation, and not when
+/// a callback already set a `Vary` header of its own.
+fn with_variances<S: HasAirshipState>(
+    response: Response,
+    status_code: StatusCode,
+    state: &S,
+) -> Response {
+    let variances = get_variances(state);
+    let is_representation =
+        status_code.is_success() || status_code == StatusCode::NotModified;
+    if !is_representation
+        || variances.is_empty()
+        || response.headers().has::<Vary>()
+    {
+        return response;
+    }
+    let vary = variances.iter().cloned().map(Ascii::new).collect();
+    response.with_header(Vary::Items(vary))
+}
+
+/// Sets the body registered for `status_code` in the request's error
+/// responses, choosing among the available media types with the request
+/// `Accept` header. The response is returned unchanged if a callback already
+/// set a body, no error response is registered or none of them is acceptable.
+fn with_error_response<S: HasAirshipState>(
+    mut response: Response,
+    status_code: StatusCode,
+    state: &S,
+) -> Response {
+    let has_body = match response.body_ref() {
+        Some(body) => !body.is_empty(),
+        None => false,
+    };
+    if has_body {
+        return response;
+    }
+    let airship_state = state.get_airship_state();
+    let responses = match airship_state.error_responses.get(&status_code) {
+        Some(responses) => responses,
+        None => return response,
+    };
+    let matched = match &airship_state.request_accept {
+        Some(accept) => {
+            let provided = responses
+                .iter()
+                .map(|(content_type, _)| content_type.clone())
+                .collect::<Vec<Mime>>();
+            map_accept_media(&provided, accept).and_then(|matched| {
+                responses
+                    .iter()
+                    .find(|(content_type, _)| *content_type == matched)
+            })
+        }
+        None => responses.first(),
+    };
+    if let Some((content_type, body)) = matched {
+        response
+            .headers_mut()
+            .set(ContentType(content_type.clone()));
+        response.set_body(body.clone());
+    }
+    response
 }
 
 ///////////////////////////////////////////////////////////////////////////////
 // B column
 ///////////////////////////////////////////////////////////////////////////////
 
-fn b13<R, S>(r: &R, _req: &Request, state: &mut S) -> BoxedFuture
+fn b13<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "b13");
-    if r.service_available(state) {
-        b12(r, _req, state)
-    } else {
-        halt(StatusCode::ServiceUnavailable, state)
-    }
+    trace(&mut state, "b13");
+    continue_with(r.service_available_async(state), move |state, available| {
+        if available {
+            b12(r, req, state)
+        } else {
+            halt(StatusCode::ServiceUnavailable, state)
+        }
+    })
 }
 
-fn b12<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn b12<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "b12");
+    trace(&mut state, "b12");
     // known method
-    let request_method = req.method();
-    let known_methods = vec![
-        Method::Get,
-        Method::Post,
-        Method::Head,
-        Method::Put,
-        Method::Delete,
-        Method::Trace,
-        Method::Connect,
-        Method::Options,
-        Method::Patch,
-    ];
-    let mut iter = known_methods.iter();
-    match iter.find(|&m| m == request_method) {
-        None => halt(StatusCode::NotImplemented, state),
-        Some(_) => b11(r, req, state),
-    }
-}
-
-fn b11<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+    let known_methods = r.known_methods(&mut state);
+    if known_methods.contains(req.method()) && r.implemented(&mut state, &req) {
+        b11(r, req, state)
+    } else {
+        halt(StatusCode::NotImplemented, state)
+    }
+}
+
+fn b11<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "b11");
-    if r.uri_too_long(state, req.uri()) {
+    trace(&mut state, "b11");
+    if r.uri_too_long(&mut state, req.uri()) {
         halt(StatusCode::UriTooLong, state)
     } else {
         b10(r, req, state)
     }
 }
 
-fn b10<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn b10<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "b10");
+    trace(&mut state, "b10");
     let request_method = req.method();
-    let allowed_methods = r.allowed_methods(state);
+    let allowed_methods = r.allowed_methods(&mut state);
     match allowed_methods.iter().find(|&m| m == request_method) {
         None => halt_with_header(
             StatusCode::MethodNotAllowed,
@@ -150,93 +242,119 @@ where
     }
 }
 
-fn b09<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn b09<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "b09");
-    if r.malformed_request(state, req) {
+    trace(&mut state, "b09");
+    if r.malformed_request(&mut state, &req) {
         halt(StatusCode::BadRequest, state)
     } else {
         b08(r, req, state)
     }
 }
 
-fn b08<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn b08<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "b08");
-    if r.is_authorized(state, req) {
-        b07(r, req, state)
-    } else {
-        halt(StatusCode::Unauthorized, state)
-    }
+    trace(&mut state, "b08");
+    continue_with(
+        r.is_authorized_async(state, &req),
+        move |state, authorized| {
+            if authorized {
+                b07(r, req, state)
+            } else {
+                halt(StatusCode::Unauthorized, state)
+            }
+        },
+    )
 }
 
-fn b07<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn b07<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "b07");
-    if r.forbidden(state, req) {
-        halt(StatusCode::Forbidden, state)
-    } else {
-        b06(r, req, state)
+    trace(&mut state, "b07");
+    continue_with(r.forbidden_async(state, &req), move |state, forbidden| {
+        if forbidden {
+            halt(StatusCode::Forbidden, state)
+        } else if is_upgrade_request(&req) {
+            upgrade(r, req, state)
+        } else {
+            b06(r, req, state)
+        }
+    })
+}
+
+// Not part of the Webmachine diagram: protocol switches are only offered to
+// the resource once the request is known to be authorized, and a request
+// the resource declines carries on as an ordinary one.
+fn upgrade<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
+where
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
+{
+    trace(&mut state, "upgrade");
+    if let Some(upgrade) = r.upgrade(&mut state, &req) {
+        if accept_upgrade(&mut state, upgrade) {
+            return halt(StatusCode::SwitchingProtocols, state);
+        }
     }
+    b06(r, req, state)
 }
 
-fn b06<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn b06<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "b06");
-    if r.valid_content_headers(state, req) {
+    trace(&mut state, "b06");
+    if r.valid_content_headers(&mut state, &req) {
         b05(r, req, state)
     } else {
         halt(StatusCode::NotImplemented, state)
     }
 }
 
-fn b05<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn b05<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "b05");
-    if r.known_content_type(state, req) {
+    trace(&mut state, "b05");
+    if r.known_content_type(&mut state, &req) {
         b04(r, req, state)
     } else {
         halt(StatusCode::UnsupportedMediaType, state)
     }
 }
 
-fn b04<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn b04<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "b04");
-    if r.entity_too_large(state, req) {
+    trace(&mut state, "b04");
+    if r.entity_too_large(&mut state, &req) {
         halt(StatusCode::PayloadTooLarge, state)
     } else {
         b03(r, req, state)
     }
 }
 
-fn b03<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn b03<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "b03");
+    trace(&mut state, "b03");
     match req.method() {
         Method::Options => {
-            let allowed_methods = r.allowed_methods(state);
+            let allowed_methods = r.allowed_methods(&mut state);
             halt_with_header(
                 StatusCode::NoContent,
                 Allow(allowed_methods),
@@ -252,35 +370,42 @@ where
 // ------------------------------------------------------------------------------
 
 fn c04<R, S>(
-    r: &R,
-    req: &Request,
-    state: &mut S,
+    r: R,
+    req: Request,
+    mut state: S,
     accept_header: &Accept,
 ) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "c04");
-    let provided = r.content_types_provided(state);
-    let result = map_accept_media(provided, &accept_header);
+    trace(&mut state, "c04");
+    let provided = r
+        .content_types_provided(&mut state)
+        .into_iter()
+        .map(|(content_type, _)| content_type)
+        .collect::<Vec<Mime>>();
+    let result = map_accept_media(&provided, &accept_header);
     match result {
-        Some(_) => {
-            matched_content_type(state, result);
+        Some(matched) => {
+            let index =
+                provided.iter().position(|provided| *provided == matched);
+            matched_content_index(&mut state, index);
+            matched_content_type(&mut state, Some(matched));
             d04(r, req, state)
         }
         None => halt(StatusCode::NotAcceptable, state),
     }
 }
 
-fn c03<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn c03<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "c03");
-    match req.headers().get::<Accept>() {
-        Some(ahdr) => c04(r, req, state, ahdr),
+    trace(&mut state, "c03");
+    match req.headers().get::<Accept>().cloned() {
+        Some(ahdr) => c04(r, req, state, &ahdr),
         None => d04(r, req, state),
     }
 }
@@ -289,34 +414,47 @@ where
 // -- D column
 // ------------------------------------------------------------------------------
 
-fn d05<R, H, S>(
-    r: &R,
-    req: &Request,
-    state: &mut S,
-    accept_lang_header: &H,
+fn d05<R, S>(
+    r: R,
+    req: Request,
+    mut state: S,
+    ranges: &[(String, Quality)],
 ) -> BoxedFuture
 where
-    H: Header,
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "d05");
-    if r.language_available(state, accept_lang_header) {
-        e05(r, req, state)
-    } else {
-        halt(StatusCode::NotAcceptable, state)
+    trace(&mut state, "d05");
+    let provided = r.languages_provided(&mut state);
+    if provided.is_empty() {
+        return e05(r, req, state);
+    }
+    match map_accept_language(&provided, ranges) {
+        Some(matched) => {
+            matched_language(&mut state, Some(matched));
+            e05(r, req, state)
+        }
+        None => halt(StatusCode::NotAcceptable, state),
     }
 }
 
-fn d04<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn d04<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "d04");
-    match req.headers().get::<AcceptLanguage>() {
-        Some(alhdr) => d05(r, req, state, alhdr),
-        None => e05(r, req, state),
+    trace(&mut state, "d04");
+    let ranges = req
+        .headers()
+        .get_raw("Accept-Language")
+        .map(accept_language_ranges);
+    match ranges {
+        Some(ranges) => d05(r, req, state, &ranges),
+        None => {
+            let first = r.languages_provided(&mut state).into_iter().next();
+            matched_language(&mut state, first);
+            e05(r, req, state)
+        }
     }
 }
 
@@ -324,31 +462,51 @@ where
 // -- E column
 // ------------------------------------------------------------------------------
 
-fn e06<R, H, S>(
-    r: &R,
-    req: &Request,
-    state: &mut S,
-    _accept_charset_header: &H,
+fn e06<R, S>(
+    r: R,
+    req: Request,
+    mut state: S,
+    accept_charset_header: &AcceptCharset,
 ) -> BoxedFuture
 where
-    H: Header,
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "e06");
-    //TODO: Implement charset negotiation
-    f06(r, req, state)
+    trace(&mut state, "e06");
+    let provided = r
+        .charsets_provided(&mut state)
+        .into_iter()
+        .map(|(charset, _)| charset)
+        .collect::<Vec<Charset>>();
+    if provided.is_empty() {
+        return f06(r, req, state);
+    }
+    match map_accept_charset(&provided, accept_charset_header) {
+        Some(matched) => {
+            matched_charset(&mut state, Some(matched));
+            f06(r, req, state)
+        }
+        None => halt(StatusCode::NotAcceptable, state),
+    }
 }
 
-fn e05<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn e05<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "e05");
-    match req.headers().get::<AcceptCharset>() {
-        Some(achdr) => e06(r, req, state, achdr),
-        None => f06(r, req, state),
+    trace(&mut state, "e05");
+    match req.headers().get::<AcceptCharset>().cloned() {
+        Some(achdr) => e06(r, req, state, &achdr),
+        None => {
+            let first = r
+                .charsets_provided(&mut state)
+                .into_iter()
+                .next()
+                .map(|(charset, _)| charset);
+            matched_charset(&mut state, first);
+            f06(r, req, state)
+        }
     }
 }
 
@@ -356,30 +514,39 @@ where
 // -- F column
 // ------------------------------------------------------------------------------
 
-fn f07<R, H, S>(
-    r: &R,
-    req: &Request,
-    state: &mut S,
-    _accept_encoding_header: &H,
+fn f07<R, S>(
+    r: R,
+    req: Request,
+    mut state: S,
+    accept_encoding_header: &AcceptEncoding,
 ) -> BoxedFuture
 where
-    H: Header,
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "f07");
-    //TODO: Implement encoding negotiation
-    f06(r, req, state)
+    trace(&mut state, "f07");
+    let provided = r
+        .encodings_provided(&mut state)
+        .into_iter()
+        .filter(encoding::is_supported)
+        .collect::<Vec<Encoding>>();
+    match map_accept_encoding(&provided, accept_encoding_header) {
+        Some(matched) => {
+            matched_encoding(&mut state, Some(matched));
+            g07(r, req, state)
+        }
+        None => halt(StatusCode::NotAcceptable, state),
+    }
 }
 
-fn f06<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn f06<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "f06");
-    match req.headers().get::<AcceptEncoding>() {
-        Some(aehdr) => f07(r, req, state, aehdr),
+    trace(&mut state, "f06");
+    match req.headers().get::<AcceptEncoding>().cloned() {
+        Some(aehdr) => f07(r, req, state, &aehdr),
         None => g07(r, req, state),
     }
 }
@@ -389,78 +556,87 @@ where
 // ------------------------------------------------------------------------------
 
 fn g11<R, S>(
-    r: &R,
-    req: &Request,
-    state: &mut S,
+    r: R,
+    req: Request,
+    mut state: S,
     etags: &[EntityTag],
 ) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "g11");
-    if etags.is_empty() {
-        halt(StatusCode::PreconditionFailed, state)
-    } else {
+    trace(&mut state, "g11");
+    // If-Match uses the strong comparison function, so a weak entity tag
+    // never matches.
+    let matched = match r.generate_etag(&mut state, &req) {
+        Some(etag) => etags.iter().any(|tag| tag.strong_eq(&etag)),
+        None => false,
+    };
+    if matched {
         h10(r, req, state)
+    } else {
+        halt(StatusCode::PreconditionFailed, state)
     }
 }
 
 fn g09<R, S>(
-    r: &R,
-    req: &Request,
-    state: &mut S,
+    r: R,
+    req: Request,
+    mut state: S,
     if_match: &IfMatch,
 ) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "g09");
+    trace(&mut state, "g09");
     match if_match {
         IfMatch::Any => h10(r, req, state),
         IfMatch::Items(etags) => g11(r, req, state, etags),
     }
 }
 
-fn g08<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn g08<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "g08");
-    match req.headers().get::<IfMatch>() {
+    trace(&mut state, "g08");
+    match req.headers().get::<IfMatch>().cloned() {
         Some(imhdr) => g09(r, req, state, &imhdr),
         None => h10(r, req, state),
     }
 }
 
-fn g07<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn g07<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "g07");
-    // TODO: set Vary headers
-    if r.resource_exists(state) {
-        g08(r, req, state)
-    } else {
-        h07(r, req, state)
-    }
+    trace(&mut state, "g07");
+    let vary = variances(&r, &mut state);
+    set_variances(&mut state, vary);
+    continue_with(r.resource_exists_async(state), move |state, exists| {
+        if exists {
+            g08(r, req, state)
+        } else {
+            h07(r, req, state)
+        }
+    })
 }
 
 // ------------------------------------------------------------------------------
 // -- H column
 // ------------------------------------------------------------------------------
 
-fn h12<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn h12<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "h12");
+    trace(&mut state, "h12");
     let m_if_unmod_since = req.headers().get::<IfUnmodifiedSince>();
-    let m_last_modified = r.last_modified(state);
+    let m_last_modified = r.last_modified(&mut state);
     match (m_if_unmod_since, m_last_modified) {
         (Some(if_unmod_since), Some(last_modified))
             if last_modified > **if_unmod_since =>
@@ -471,32 +647,25 @@ where
     }
 }
 
-fn h11<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn h11<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "h11");
-    // TODO: Revisit this to understand how hyper handles invalid HttpDate values
-    // let m_header_str = req.headers().get_raw("if-unmodified-since");
-    // let valid_date = match m_header_str {
-    //     Some(header_raw) => Header::parse_header(header_raw).is_ok(),
-    //     None             => &false
-    // };
-    let valid_date = true;
-    if valid_date {
+    trace(&mut state, "h11");
+    if is_valid_date::<IfUnmodifiedSince>(&req) {
         h12(r, req, state)
     } else {
         i12(r, req, state)
     }
 }
 
-fn h10<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn h10<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "h10");
+    trace(&mut state, "h10");
     if req.headers().has::<IfUnmodifiedSince>() {
         h11(r, req, state)
     } else {
@@ -504,12 +673,12 @@ where
     }
 }
 
-fn h07<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn h07<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "h07");
+    trace(&mut state, "h07");
     match req.headers().get::<IfMatch>() {
         Some(IfMatch::Any) => halt(StatusCode::PreconditionFailed, state),
         _ => i07(r, req, state),
@@ -521,74 +690,75 @@ where
 // ------------------------------------------------------------------------------
 
 fn i13<R, S>(
-    r: &R,
-    req: &Request,
-    state: &mut S,
+    r: R,
+    req: Request,
+    mut state: S,
     if_none_match: &IfNoneMatch,
 ) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "i13");
+    trace(&mut state, "i13");
     match if_none_match {
         IfNoneMatch::Any => j18(r, req, state),
         IfNoneMatch::Items(etags) => k13(r, req, state, &etags),
     }
 }
 
-fn i12<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn i12<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "i12");
-    match req.headers().get::<IfNoneMatch>() {
-        Some(inmhdr) => i13(r, req, state, inmhdr),
+    trace(&mut state, "i12");
+    match req.headers().get::<IfNoneMatch>().cloned() {
+        Some(inmhdr) => i13(r, req, state, &inmhdr),
         None => l13(r, req, state),
     }
 }
 
-fn i07<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn i07<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "i07");
+    trace(&mut state, "i07");
     match req.method() {
         Method::Put => i04(r, req, state),
         _ => k07(r, req, state),
     }
 }
 
-fn i04<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn i04<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "i04");
-    match r.moved_permanently(state) {
-        Some(location) => {
-            set_response_header(state, Location::new(location));
-            halt(StatusCode::MovedPermanently, state)
+    trace(&mut state, "i04");
+    continue_with(r.moved_permanently_async(state), move |mut state, moved| {
+        match moved {
+            Some(location) => {
+                set_response_header(&mut state, Location::new(location));
+                halt(StatusCode::MovedPermanently, state)
+            }
+            None => p03(r, req, state),
         }
-        None => p03(r, req, state),
-    }
+    })
 }
 
 // ------------------------------------------------------------------------------
 // -- J column
 // ------------------------------------------------------------------------------
 
-fn j18<R, S>(_r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn j18<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "j18");
+    trace(&mut state, "j18");
     match req.method() {
-        Method::Get => halt(StatusCode::NotModified, state),
-        Method::Head => halt(StatusCode::NotModified, state),
+        Method::Get | Method::Head => not_modified(&r, &req, state),
         _ => halt(StatusCode::PreconditionFailed, state),
     }
 }
@@ -598,113 +768,117 @@ where
 // ------------------------------------------------------------------------------
 
 fn k13<R, S>(
-    r: &R,
-    req: &Request,
-    state: &mut S,
+    r: R,
+    req: Request,
+    mut state: S,
     etags: &[EntityTag],
 ) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "k13");
-    if etags.is_empty() {
-        l13(r, req, state)
-    } else {
+    trace(&mut state, "k13");
+    // If-None-Match uses the weak comparison function, so a weak entity tag
+    // matches the strong tag with the same opaque value.
+    let matched = match r.generate_etag(&mut state, &req) {
+        Some(etag) => etags.iter().any(|tag| tag.weak_eq(&etag)),
+        None => false,
+    };
+    if matched {
         j18(r, req, state)
+    } else {
+        l13(r, req, state)
     }
 }
 
-fn k07<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn k07<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "k07");
-    if r.previously_existed(state) {
-        k05(r, req, state)
-    } else {
-        l07(r, req, state)
-    }
+    trace(&mut state, "k07");
+    continue_with(r.previously_existed_async(state), move |state, existed| {
+        if existed {
+            k05(r, req, state)
+        } else {
+            l07(r, req, state)
+        }
+    })
 }
 
-fn k05<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn k05<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "k05");
-    match r.moved_permanently(state) {
-        Some(location) => {
-            set_response_header(state, Location::new(location));
-            halt(StatusCode::MovedPermanently, state)
+    trace(&mut state, "k05");
+    continue_with(r.moved_permanently_async(state), move |mut state, moved| {
+        match moved {
+            Some(location) => {
+                set_response_header(&mut state, Location::new(location));
+                halt(StatusCode::MovedPermanently, state)
+            }
+            None => l05(r, req, state),
         }
-        None => l05(r, req, state),
-    }
+    })
 }
 
 // ------------------------------------------------------------------------------
 // -- L column
 // ------------------------------------------------------------------------------
 
-fn l17<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn l17<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "l17");
-    let m_if_mod_since = req.headers().get::<IfModifiedSince>();
-    let m_last_modified = r.last_modified(state);
+    trace(&mut state, "l17");
+    let m_if_mod_since = req.headers().get::<IfModifiedSince>().cloned();
+    let m_last_modified = r.last_modified(&mut state);
     match (m_if_mod_since, m_last_modified) {
         (Some(if_mod_since), Some(last_modified))
-            if **if_mod_since > last_modified =>
+            if last_modified <= *if_mod_since =>
         {
-            m16(r, req, state)
+            not_modified(&r, &req, state)
         }
-        _ => halt(StatusCode::NotModified, state),
+        _ => m16(r, req, state),
     }
 }
 
-fn l15<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn l15<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "l15");
+    trace(&mut state, "l15");
     let m_if_mod_since = req.headers().get::<IfModifiedSince>();
     match m_if_mod_since {
-        Some(if_mod_since) if **if_mod_since > request_time(state) => {
+        Some(if_mod_since) if **if_mod_since > request_time(&state) => {
             m16(r, req, state)
         }
         _ => l17(r, req, state),
     }
 }
 
-fn l14<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn l14<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "l14");
-    // TODO: Revisit this to understand how hyper handles invalid HttpDate values
-    // let valid_date = req.headers()
-    //     .get_raw("if-modified-since")
-    //     .and_then(str::parse::<HttpDate>())
-    //     .is_some();
-    let valid_date = true;
-    if valid_date {
+    trace(&mut state, "l14");
+    if is_valid_date::<IfModifiedSince>(&req) {
         l15(r, req, state)
     } else {
         m16(r, req, state)
     }
 }
 
-fn l13<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn l13<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "l13");
+    trace(&mut state, "l13");
     if req.headers().has::<IfModifiedSince>() {
         l14(r, req, state)
     } else {
@@ -712,81 +886,98 @@ where
     }
 }
 
-fn l07<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn l07<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "l07");
+    trace(&mut state, "l07");
     match req.method() {
         Method::Post => m07(r, req, state),
         _ => halt(StatusCode::NotFound, state),
     }
 }
 
-fn l05<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn l05<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "l05");
-    match r.moved_temporarily(state) {
-        Some(location) => {
-            set_response_header(state, Location::new(location));
-            halt(StatusCode::TemporaryRedirect, state)
+    trace(&mut state, "l05");
+    continue_with(r.moved_temporarily_async(state), move |mut state, moved| {
+        match moved {
+            Some(location) => {
+                set_response_header(&mut state, Location::new(location));
+                halt(StatusCode::TemporaryRedirect, state)
+            }
+            None => m05(r, req, state),
         }
-        None => m05(r, req, state),
-    }
+    })
 }
 
 // ------------------------------------------------------------------------------
 // -- M column
 // ------------------------------------------------------------------------------
 
-fn m20<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn m20<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "m20");
-    match (r.delete_resource(state, req), r.delete_completed(state)) {
-        (true, true) => o20(r, req, state),
-        (true, false) => halt(StatusCode::Accepted, state),
-        _ => halt(StatusCode::InternalServerError, state),
-    }
+    trace(&mut state, "m20");
+    continue_with(
+        r.delete_resource_async(state, &req),
+        move |state, deleted| {
+            if !deleted {
+                return halt(StatusCode::InternalServerError, state);
+            }
+            continue_with(
+                r.delete_completed_async(state),
+                move |state, completed| {
+                    if completed {
+                        o20(r, req, state)
+                    } else {
+                        halt(StatusCode::Accepted, state)
+                    }
+                },
+            )
+        },
+    )
 }
 
-fn m16<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn m16<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "m16");
+    trace(&mut state, "m16");
     match req.method() {
         Method::Delete => m20(r, req, state),
         _ => n16(r, req, state),
     }
 }
 
-fn m07<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn m07<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "m07");
-    if r.allow_missing_post(state) {
-        n11(r, req, state)
-    } else {
-        halt(StatusCode::NotFound, state)
-    }
+    trace(&mut state, "m07");
+    continue_with(r.allow_missing_post_async(state), move |state, allowed| {
+        if allowed {
+            n11(r, req, state)
+        } else {
+            halt(StatusCode::NotFound, state)
+        }
+    })
 }
 
-fn m05<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn m05<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "m05");
+    trace(&mut state, "m05");
     match req.method() {
         Method::Post => n05(r, req, state),
         _ => halt(StatusCode::Gone, state),
@@ -797,129 +988,204 @@ where
 // -- N column
 // ------------------------------------------------------------------------------
 
-fn n16<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn n16<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "n16");
+    trace(&mut state, "n16");
     match req.method() {
         Method::Post => n11(r, req, state),
         _ => o16(r, req, state),
     }
 }
 
-fn n11<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn n11<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "n11");
-    let post_response = r.process_post(state, req);
-    process_post_action(r, req, state, post_response)
+    trace(&mut state, "n11");
+    continue_with(
+        r.process_post_async(state, &req),
+        move |state, post_response| {
+            process_post_action(r, req, state, post_response)
+        },
+    )
 }
 
-fn n05<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn n05<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "n05");
-    if r.allow_missing_post(state) {
-        n11(r, req, state)
-    } else {
-        halt(StatusCode::Gone, state)
-    }
+    trace(&mut state, "n05");
+    continue_with(r.allow_missing_post_async(state), move |state, allowed| {
+        if allowed {
+            n11(r, req, state)
+        } else {
+            halt(StatusCode::Gone, state)
+        }
+    })
 }
 
 // ------------------------------------------------------------------------------
 // -- O column
 // ------------------------------------------------------------------------------
 
-fn o20<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn o20<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "o20");
-    if is_response_empty(state) {
-        halt(StatusCode::Created, state)
+    trace(&mut state, "o20");
+    if is_response_empty(&state) {
+        halt(StatusCode::NoContent, state)
     } else {
         o18(r, req, state)
     }
 }
 
-fn o18<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn o18<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "o18");
-    if r.multiple_choices(state) {
+    trace(&mut state, "o18");
+    if r.multiple_choices(&mut state) {
         halt(StatusCode::MultipleChoices, state)
     } else {
+        let is_head = *req.method() == Method::Head;
+        let mut measure_body = false;
         match req.method() {
-            // TODO: set expiration, etc. headers
             Method::Get | Method::Head => {
-                let (content_type, body_fn) = get_matched_content_type(state)
-                    .take()
-                    .unwrap_or_else(|| {
-                        // TODO: This unwrap should be safe because if we've
-                        // made it this far in the decision processing then we
-                        // know there is at least one entry in the
-                        // content_types_provided vector, but I want to confirm
-                        // this is absolutlely the case.
-                        r.content_types_provided(state).first().unwrap().clone()
-                    });
-                set_response_header(
-                    state,
-                    ContentType(Mime::clone(&content_type)),
-                );
-                let response_body = body_fn(req);
-                set_response_body(state, response_body);
+                // Without an Accept header the first type provided is sent.
+                let index = get_matched_content_index(&state).unwrap_or(0);
+                let provided =
+                    r.content_types_provided(&mut state).into_iter().nth(index);
+                let (content_type, body_fn) = match provided {
+                    Some(provided) => provided,
+                    None => {
+                        return halt(StatusCode::InternalServerError, state)
+                    }
+                };
+                let (content_type, converter) =
+                    with_negotiated_charset(&r, &mut state, content_type);
+                set_content_headers(&mut state, &content_type);
+                let is_transformed = converter.is_some()
+                    || get_matched_encoding(&state)
+                        .and_then(encoding::encoder)
+                        .is_some();
+                // The ranges are of the identity bytes, which share their
+                // validators with a converted or content coded body, so
+                // splicing them into one would corrupt it.
+                let byte_ranges = if is_transformed {
+                    None
+                } else {
+                    r.byte_ranges(&mut state, &req)
+                };
+                if byte_ranges.is_some() {
+                    set_response_header(
+                        &mut state,
+                        AcceptRanges(vec![RangeUnit::Bytes]),
+                    );
+                }
+                let requested = match byte_ranges {
+                    Some(byte_ranges) if !is_head => {
+                        requested_ranges(&r, &req, &mut state)
+                            .map(|specs| (byte_ranges, specs))
+                    }
+                    _ => None,
+                };
+                if let Some(((length, producer), specs)) = requested {
+                    set_cache_headers(&r, &req, &mut state);
+                    return byte_range_response(
+                        req,
+                        state,
+                        &content_type,
+                        (length, producer),
+                        &specs,
+                    );
+                }
+                let length = if is_head && !is_transformed {
+                    r.content_length(&mut state, &req)
+                } else {
+                    None
+                };
+                match length {
+                    Some(length) => {
+                        set_response_header(&mut state, ContentLength(length))
+                    }
+                    None => {
+                        let response_body = match body_fn(&mut state, &req) {
+                            Ok(response_body) => response_body,
+                            Err(error) => return halt_with_error(error, state),
+                        };
+                        let is_stream = response_body.is_stream();
+                        set_representation_body(
+                            &mut state,
+                            response_body,
+                            converter,
+                        );
+                        if is_head && is_stream {
+                            // A stream may never end, so rather than
+                            // measuring it the response goes without a
+                            // Content-Length.
+                            take_response_stream(&mut state);
+                        } else {
+                            measure_body = is_head;
+                        }
+                    }
+                }
             }
-            _ => (),
+            _ => set_accepted_entity(&r, &mut state),
         };
-        if let Some(etag) = r.generate_etag(state, req) {
-            set_response_header(state, ETag(etag));
-        }
-        if let Some(modified) = r.last_modified(state) {
-            set_response_header(state, LastModified(modified));
+        set_cache_headers(&r, &req, &mut state);
+        if measure_body {
+            head_response(state)
+        } else {
+            halt(StatusCode::Ok, state)
         }
-        halt_with_response(StatusCode::Ok, state)
     }
 }
 
-fn o16<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn o16<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "o16");
+    trace(&mut state, "o16");
     match req.method() {
         Method::Put => o14(r, req, state),
         _ => o17(r, req, state),
     }
 }
 
-fn o17<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn o17<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "o17");
+    trace(&mut state, "o17");
     match req.method() {
         Method::Patch => {
-            let accepted = r.patch_content_types_accepted(state);
-            let result = req
-                .headers()
-                .get::<ContentType>()
-                .and_then(|ct_hdr| map_content_media::<()>(accepted, ct_hdr));
+            let accepted = r.patch_content_types_accepted(&mut state);
+            let result =
+                req.headers().get::<ContentType>().and_then(|ct_hdr| {
+                    map_content_media::<S, ()>(accepted, ct_hdr)
+                });
             match result {
-                Some(action) => {
-                    action(req);
-                    o20(r, req, state)
-                }
+                Some(action) => read_request_body(
+                    r,
+                    req,
+                    state,
+                    move |r, req, mut state, body| {
+                        if let Err(error) = action(&mut state, &req, &body) {
+                            return halt_with_error(error, state);
+                        }
+                        o20(r, req, state)
+                    },
+                ),
                 None => halt(StatusCode::UnsupportedMediaType, state),
             }
         }
@@ -927,69 +1193,86 @@ where
     }
 }
 
-fn o14<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn o14<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "o14");
-    if r.is_conflict(state) {
-        halt(StatusCode::Conflict, state)
-    } else {
-        let accepted = r.content_types_accepted(state);
+    trace(&mut state, "o14");
+    continue_with(r.is_conflict_async(state), move |mut state, conflict| {
+        if conflict {
+            return halt(StatusCode::Conflict, state);
+        }
+        let accepted = r.content_types_accepted(&mut state);
         let result = req
             .headers()
             .get::<ContentType>()
-            .and_then(|ct_hdr| map_content_media::<()>(accepted, ct_hdr));
+            .and_then(|ct_hdr| map_content_media::<S, ()>(accepted, ct_hdr));
         match result {
-            Some(action) => {
-                action(req);
-                p11(r, req, state)
-            }
+            Some(action) => read_request_body(
+                r,
+                req,
+                state,
+                move |r, req, mut state, body| {
+                    if let Err(error) = action(&mut state, &req, &body) {
+                        return halt_with_error(error, state);
+                    }
+                    p11(r, req, state)
+                },
+            ),
             None => halt(StatusCode::UnsupportedMediaType, state),
         }
-    }
+    })
 }
 
 // ------------------------------------------------------------------------------
 // -- P column
 // ------------------------------------------------------------------------------
 
-fn p11<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn p11<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "p11");
-    if req.headers().has::<Location>() {
+    trace(&mut state, "p11");
+    if has_response_header::<Location, S>(&state) {
+        set_accepted_entity(&r, &mut state);
         halt(StatusCode::Created, state)
     } else {
         o20(r, req, state)
     }
 }
 
-fn p03<R, S>(r: &R, req: &Request, state: &mut S) -> BoxedFuture
+fn p03<R, S>(r: R, req: Request, mut state: S) -> BoxedFuture
 where
-    R: Webmachine,
-    S: HasAirshipState,
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
-    trace(state, "p03");
-    if r.is_conflict(state) {
-        halt(StatusCode::Conflict, state)
-    } else {
-        let accepted = r.content_types_accepted(state);
+    trace(&mut state, "p03");
+    continue_with(r.is_conflict_async(state), move |mut state, conflict| {
+        if conflict {
+            return halt(StatusCode::Conflict, state);
+        }
+        let accepted = r.content_types_accepted(&mut state);
         let result = req
             .headers()
             .get::<ContentType>()
-            .and_then(|ct_hdr| map_content_media::<()>(accepted, ct_hdr));
+            .and_then(|ct_hdr| map_content_media::<S, ()>(accepted, ct_hdr));
         match result {
-            Some(action) => {
-                action(req);
-                p11(r, req, state)
-            }
+            Some(action) => read_request_body(
+                r,
+                req,
+                state,
+                move |r, req, mut state, body| {
+                    if let Err(error) = action(&mut state, &req, &body) {
+                        return halt_with_error(error, state);
+                    }
+                    p11(r, req, state)
+                },
+            ),
             None => halt(StatusCode::UnsupportedMediaType, state),
         }
-    }
+    })
 }
 
 // ------------------------------------------------------------------------------
@@ -997,138 +1280,3295 @@ where
 // ------------------------------------------------------------------------------
 
 /// Matches a list of server-side parsing options against a the client-side
-/// content value.
-fn map_content_media<T>(
-    provided: Vec<(Mime, fn(&Request) -> T)>,
+/// content value. Each accepted type is treated as a media range, so
+/// `application/*` and `application/*+json` accept any matching request type,
+/// and parameters are only compared when the accepted type names them. When
+/// several accepted types match, the most specific one is used.
+fn map_content_media<S, T>(
+    provided: Vec<(Mime, BodyAcceptor<S, T>)>,
     content_type: &ContentType,
-) -> Option<fn(&Request) -> T> {
-    let mut action_match = None;
+) -> Option<BodyAcceptor<S, T>> {
+    let mut action_match: Option<((u8, usize), BodyAcceptor<S, T>)> = None;
 
-    // Iterate through all of the provided Content-Types for the
-    // resource and look for a match.
-    for (ct_hdr, action) in &provided {
-        if ct_hdr == &content_type.0 {
-            action_match = Some(*action);
-            break;
+    for (range, action) in provided {
+        let specificity = match media_range_specificity(&range, &content_type.0)
+        {
+            Some(specificity) => specificity,
+            None => continue,
+        };
+        let is_better = match action_match {
+            Some((best, _)) => specificity > best,
+            None => true,
+        };
+        if is_better {
+            action_match = Some((specificity, action));
         }
     }
-    action_match
+    action_match.map(|(_, action)| action)
 }
 
 /// Matches a list of server-side resource options against a quality-marked list
-/// of client-side preferences.
-fn map_accept_media(
-    provided: Vec<(Mime, fn(&Request) -> Body)>,
-    accept: &Accept,
-) -> Option<(Mime, fn(&Request) -> Body)> {
-    let zero_quality = q(0);
-    let mut match_quality = q(0);
-    let mut type_match = None;
-
-    for a_hdr in accept.iter() {
-        if a_hdr.quality == zero_quality {
-            // Do not match Accept header values with a quality of zero
-            break;
-        } else {
-            // Iterate through all of the provided Content-Types for the
-            // resource and find the match with the highest quality value.
-            for (ct_hdr, body_fn) in &provided {
-                if (a_hdr.item == mime::STAR_STAR
-                    && a_hdr.quality > match_quality)
-                    || (a_hdr.item.type_() == ct_hdr.type_()
-                        && a_hdr.quality > match_quality
-                        && (a_hdr.item.subtype() == ct_hdr.subtype()
-                            || a_hdr.item.subtype() == mime::STAR))
-                {
-                    type_match = Some((ct_hdr.clone(), *body_fn));
-                    match_quality = a_hdr.quality;
-                }
+/// of client-side preferences, following RFC 7231 section 5.3.2.
+///
+/// Each provided type takes the quality of the most specific media range that
+/// matches it: `type/subtype` is more specific than `type/*`, which is more
+/// specific than `*/*`, and a range with parameters is more specific than the
+/// same range without them. A range with parameters only matches types that
+/// carry the same values for them. A type whose most specific range has a
+/// quality of zero is never chosen. Among types of equal quality, one matched
+/// by a more specific range is preferred, and after that the order of
+/// `provided` decides.
+fn map_accept_media(provided: &[Mime], accept: &Accept) -> Option<Mime> {
+    let mut best: Option<(&Mime, Quality, (u8, usize))> = None;
+    for content_type in provided {
+        let matched = accept
+            .iter()
+            .filter_map(|range| {
+                media_range_specificity(&range.item, content_type)
+                    .map(|specificity| (range.quality, specificity))
+            })
+            .max_by_key(|(_, specificity)| *specificity);
+        let (quality, specificity) = match matched {
+            Some(matched) if matched.0 > q(0) => matched,
+            _ => continue,
+        };
+        let is_better = match best {
+            Some((_, best_quality, best_specificity)) => {
+                (quality, specificity) > (best_quality, best_specificity)
             }
+            None => true,
+        };
+        if is_better {
+            best = Some((content_type, quality, specificity));
+        }
+    }
+    best.map(|(content_type, _, _)| content_type.clone())
+}
+
+/// How specifically the media `range` matches `content_type`, or `None` if
+/// it does not match. Ranges compare first by how much of the type they name
+/// and then by how many parameters they carry. A structured syntax suffix
+/// range such as `application/*+json` matches `application/json` as well as
+/// any `application` type with the `+json` suffix.
+fn media_range_specificity(
+    range: &Mime,
+    content_type: &Mime,
+) -> Option<(u8, usize)> {
+    let specificity = if range.type_() == mime::STAR {
+        if range.subtype() != mime::STAR {
+            return None;
+        }
+        0
+    } else if range.type_() != content_type.type_() {
+        return None;
+    } else if range.subtype() == mime::STAR {
+        match range.suffix() {
+            None => 1,
+            Some(suffix)
+                if content_type.suffix() == Some(suffix)
+                    || content_type.subtype() == suffix =>
+            {
+                2
+            }
+            Some(_) => return None,
+        }
+    } else if range.subtype() == content_type.subtype()
+        && range.suffix() == content_type.suffix()
+    {
+        3
+    } else {
+        return None;
+    };
+    let mut params = 0;
+    for (name, value) in range.params() {
+        let matches = match content_type.get_param(name) {
+            Some(provided) if name == mime::CHARSET => {
+                provided.as_str().eq_ignore_ascii_case(value.as_str())
+            }
+            Some(provided) => provided == value,
+            None => false,
+        };
+        if !matches {
+            return None;
+        }
+        params += 1;
+    }
+    Some((specificity, params))
+}
+
+/// Error raised while collecting a request body.
+enum BodyError {
+    TooLarge,
+    Hyper(hyper::Error),
+}
+
+/// Returns the entry of `provided` with the highest quality greater than
+/// zero, preferring earlier entries when qualities are equal.
+fn best_quality<T, F>(provided: &[T], quality: F) -> Option<T>
+where
+    T: Clone,
+    F: Fn(&T) -> Quality,
+{
+    let mut best: Option<(&T, Quality)> = None;
+    for item in provided {
+        let item_quality = quality(item);
+        let is_better = match best {
+            Some((_, best_quality)) => item_quality > best_quality,
+            None => item_quality > q(0),
+        };
+        if is_better {
+            best = Some((item, item_quality));
+        }
+    }
+    best.map(|(item, _)| item.clone())
+}
+
+/// Chooses the charset of the response from the charsets the resource
+/// provides and the quality values in the `Accept-Charset` header, as
+/// described in RFC 7231 section 5.3.3. Charset names are compared without
+/// regard to case, and a charset without an entry of its own takes the
+/// quality of a `*` entry.
+fn map_accept_charset(
+    provided: &[Charset],
+    accept: &AcceptCharset,
+) -> Option<Charset> {
+    let wildcard = Charset::Ext(String::from("*"));
+    best_quality(provided, |charset| {
+        let name = charset.to_string();
+        let explicit = accept
+            .iter()
+            .find(|a| a.item.to_string().eq_ignore_ascii_case(&name));
+        let any = accept.iter().find(|a| a.item == wildcard);
+        match explicit.or(any) {
+            Some(a) => a.quality,
+            None => q(0),
+        }
+    })
+}
+
+/// Chooses the language of the response from the languages the resource
+/// provides and the `Accept-Language` header. Each provided tag takes the
+/// quality of the most specific language range that matches it under the
+/// basic filtering scheme of RFC 4647 section 3.3.1. If that leaves nothing
+/// acceptable, the ranges are tried in order of quality with the lookup
+/// scheme of section 3.4, so that a range of `en-GB` can still be served
+/// `en`. A header without any range that could be parsed expresses no
+/// preference, and the first provided tag is used.
+fn map_accept_language(
+    provided: &[LanguageTag],
+    ranges: &[(String, Quality)],
+) -> Option<LanguageTag> {
+    if ranges.is_empty() {
+        return provided.first().cloned();
+    }
+    best_quality(provided, |tag| {
+        filtered_language_quality(tag, ranges).unwrap_or(q(0))
+    })
+    .or_else(|| {
+        let mut lookup_ranges = ranges
+            .iter()
+            .filter(|(_, quality)| *quality > q(0))
+            .collect::<Vec<_>>();
+        lookup_ranges.sort_by_key(|(_, quality)| Reverse(*quality));
+        lookup_ranges
+            .into_iter()
+            .find_map(|(range, _)| lookup_language(provided, range, ranges))
+    })
+}
+
+/// Parses the language ranges of a raw `Accept-Language` header, lowercased
+/// and with their qualities. Hyper's `AcceptLanguage` is not used, as it only
+/// keeps ranges that parse as language tags and so silently drops the `*`
+/// range.
+fn accept_language_ranges(raw: &Raw) -> Vec<(String, Quality)> {
+    raw.iter()
+        .filter_map(|line| std::str::from_utf8(line).ok())
+        .flat_map(|line| line.split(','))
+        .map(str::trim)
+        .filter(|range| !range.is_empty())
+        .filter_map(|range| range.parse::<QualityItem<String>>().ok())
+        .map(|range| (range.item.to_ascii_lowercase(), range.quality))
+        .collect()
+}
+
+/// The quality of the most specific range in `ranges` that matches `tag`
+/// under basic filtering, where a range matches a tag equal to it or a tag
+/// that extends it with further subtags, and the `*` range matches any tag
+/// but is less specific than all others.
+fn filtered_language_quality(
+    tag: &LanguageTag,
+    ranges: &[(String, Quality)],
+) -> Option<Quality> {
+    let tag = tag.to_string().to_ascii_lowercase();
+    ranges
+        .iter()
+        .filter(|(range, _)| {
+            range == "*"
+                || tag == *range
+                || tag.starts_with(&format!("{}-", range))
+        })
+        .max_by_key(|(range, _)| if range == "*" { 0 } else { range.len() })
+        .map(|(_, quality)| *quality)
+}
+
+/// Looks for a provided tag equal to `range`, removing subtags from the end
+/// of the range until one is found. Tags that a range in `ranges` explicitly
+/// refuses with a quality of zero are never chosen.
+fn lookup_language(
+    provided: &[LanguageTag],
+    range: &str,
+    ranges: &[(String, Quality)],
+) -> Option<LanguageTag> {
+    let mut range = range;
+    loop {
+        let found = provided.iter().find(|tag| {
+            tag.to_string().eq_ignore_ascii_case(range)
+                && filtered_language_quality(tag, ranges) != Some(q(0))
+        });
+        if let Some(tag) = found {
+            return Some(tag.clone());
+        }
+        range = match range.rfind('-') {
+            Some(index) => &range[..index],
+            None => return None,
+        };
+        // A single-character subtag is not removed on its own, as in
+        // `zh-Hant-CN-x-private1`.
+        if range.len() > 2 && range.as_bytes()[range.len() - 2] == b'-' {
+            range = &range[..range.len() - 2];
+        }
+    }
+}
+
+/// Chooses the coding to apply to the response from the codings the resource
+/// provides and the quality values in the `Accept-Encoding` header, as
+/// described in RFC 7231 section 5.3.4. A coding without an entry of its own
+/// takes the quality of a `*` entry. Without either, `identity` is still
+/// acceptable but preferred least, and any other coding is not acceptable.
+/// Ties are broken by the order of `provided`.
+fn map_accept_encoding(
+    provided: &[Encoding],
+    accept: &AcceptEncoding,
+) -> Option<Encoding> {
+    let wildcard = Encoding::EncodingExt(String::from("*"));
+    best_quality(provided, |coding| {
+        let explicit = accept.iter().find(|a| a.item == *coding);
+        let any = accept.iter().find(|a| a.item == wildcard);
+        match explicit.or(any) {
+            Some(a) => a.quality,
+            None if *coding == Encoding::Identity => q(1),
+            None => q(0),
+        }
+    })
+}
+
+/// Adds a `charset` parameter to `content_type`, unless the resource already
+/// gave it one.
+fn with_charset_param(content_type: &Mime, charset: &Charset) -> Mime {
+    if content_type.get_param(mime::CHARSET).is_some() {
+        return content_type.clone();
+    }
+    format!("{}; charset={}", content_type, charset)
+        .parse()
+        .unwrap_or_else(|_| content_type.clone())
+}
+
+/// Adds the negotiated charset to `content_type`, along with the resource's
+/// converter for that charset if it has one.
+fn with_negotiated_charset<R, S>(
+    r: &R,
+    state: &mut S,
+    content_type: Mime,
+) -> (Mime, Option<CharsetConverter>)
+where
+    R: Webmachine,
+    S: HasAirshipState,
+{
+    match get_matched_charset(state).cloned() {
+        Some(matched) => {
+            let converter = r
+                .charsets_provided(state)
+                .into_iter()
+                .find(|(charset, _)| *charset == matched)
+                .and_then(|(_, converter)| converter);
+            (with_charset_param(&content_type, &matched), converter)
+        }
+        None => (content_type, None),
+    }
+}
+
+fn set_content_headers<S: HasAirshipState>(state: &mut S, content_type: &Mime) {
+    set_response_header(state, ContentType(content_type.clone()));
+    if let Some(language) = get_matched_language(state).cloned() {
+        set_response_header(state, ContentLanguage(vec![qitem(language)]));
+    }
+}
+
+/// Sends a body that a `POST`, `PUT` or `PATCH` handler set through the
+/// request state as the negotiated representation. The body is labelled with
+/// the negotiated media type, language and charset, and converted to that
+/// charset, unless the handler set a `Content-Type` of its own, in which case
+/// it is only content coded.
+fn set_accepted_entity<R, S>(r: &R, state: &mut S)
+where
+    R: Webmachine,
+    S: HasAirshipState,
+{
+    if is_response_empty(state) {
+        return;
+    }
+    let mut converter = None;
+    if !has_response_header::<ContentType, S>(state) {
+        let content_type = match get_matched_content_type(state).clone() {
+            Some(matched) => Some(matched),
+            None => r
+                .content_types_provided(state)
+                .into_iter()
+                .next()
+                .map(|(content_type, _)| content_type),
+        };
+        if let Some(content_type) = content_type {
+            let (content_type, charset_converter) =
+                with_negotiated_charset(r, state, content_type);
+            set_content_headers(state, &content_type);
+            converter = charset_converter;
+        }
+    }
+    let is_encoded = get_matched_encoding(state)
+        .and_then(encoding::encoder)
+        .is_some();
+    if converter.is_some() || is_encoded {
+        let body = take_response_body(state);
+        set_representation_body(state, ProvidedBody::Stream(body), converter);
+    }
+}
+
+/// Sets the representation produced in `o18` as the response body, passing
+/// it through the negotiated charset converter and content coding. A full
+/// body that needs neither is sent as it is; anything else is sent as a
+/// stream.
+fn set_representation_body<S: HasAirshipState>(
+    state: &mut S,
+    body: ProvidedBody,
+    converter: Option<CharsetConverter>,
+) {
+    let encoder = get_matched_encoding(state).cloned().and_then(|matched| {
+        encoding::encoder(&matched).map(|encoder| (matched, encoder))
+    });
+    let mut stream: ResponseBody = match body {
+        ProvidedBody::Full(body)
+            if converter.is_none() && encoder.is_none() =>
+        {
+            set_response_body(state, body);
+            return;
         }
+        ProvidedBody::Full(body) => Box::new(body),
+        ProvidedBody::Stream(stream) => stream,
+    };
+    if let Some(mut convert) = converter {
+        stream =
+            Box::new(stream.map(move |chunk| Chunk::from(convert(&chunk))));
+    }
+    if let Some((matched, encoder)) = encoder {
+        set_response_header(state, ContentEncoding(vec![matched]));
+        stream = Box::new(EncodedBody::new(stream, encoder));
     }
-    type_match
+    set_response_stream(state, stream);
 }
 
-fn append_request_path(req: &Request, path_segments: &[String]) -> String {
-    let path_suffix: String = path_segments
+/// The request headers that the negotiated representation depends on: the
+/// `Accept` headers for which the resource offers more than one choice,
+/// followed by the resource's own `variances`.
+fn variances<R, S>(r: &R, state: &mut S) -> Vec<String>
+where
+    R: Webmachine,
+    S: HasAirshipState,
+{
+    let mut vary = Vec::new();
+    if r.content_types_provided(state).len() > 1 {
+        vary.push(String::from("Accept"));
+    }
+    if r.languages_provided(state).len() > 1 {
+        vary.push(String::from("Accept-Language"));
+    }
+    if r.charsets_provided(state).len() > 1 {
+        vary.push(String::from("Accept-Charset"));
+    }
+    let encodings = r
+        .encodings_provided(state)
         .iter()
-        .cloned()
-        .intersperse(",".to_string())
-        .collect();
-    [req.path(), &path_suffix].concat()
+        .filter(|coding| encoding::is_supported(coding))
+        .count();
+    if encodings > 1 {
+        vary.push(String::from("Accept-Encoding"));
+    }
+    vary.extend(r.variances(state));
+    vary
+}
+
+/// Sets the validator and expiration headers of a successful response from
+/// the resource's callbacks.
+fn set_cache_headers<R, S>(r: &R, req: &Request, state: &mut S)
+where
+    R: Webmachine,
+    S: HasAirshipState,
+{
+    if let Some(etag) = r.generate_etag(state, req) {
+        set_response_header(state, ETag(etag));
+    }
+    if let Some(modified) = r.last_modified(state) {
+        set_response_header(state, LastModified(modified));
+    }
+    if let Some(expires) = r.expires(state) {
+        set_response_header(state, Expires(expires));
+    }
+    let directives = r.cache_control(state);
+    if !directives.is_empty() {
+        set_response_header(state, CacheControl(directives));
+    }
+}
+
+/// Halts with `304 Not Modified`. RFC 7232 section 4.1 requires the response
+/// to carry the same validator and caching headers a `200 OK` would have.
+fn not_modified<R, S>(r: &R, req: &Request, mut state: S) -> BoxedFuture
+where
+    R: Webmachine,
+    S: HasAirshipState,
+{
+    set_cache_headers(r, req, &mut state);
+    halt(StatusCode::NotModified, state)
 }
 
-fn create<R, S>(
+/// The byte ranges requested by a `GET` request, or `None` if its `Range`
+/// header should not be honoured. As described in RFC 7233 section 3.2, the
+/// ranges are ignored when an `If-Range` header does not match the current
+/// representation: an entity tag must match the resource's ETag with the
+/// strong comparison function, and a date must equal its Last-Modified.
+fn requested_ranges<R, S>(
     r: &R,
     req: &Request,
     state: &mut S,
+) -> Option<Vec<ByteRangeSpec>>
+where
+    R: Webmachine,
+    S: HasAirshipState,
+{
+    let specs = match req.headers().get::<Range>() {
+        Some(Range::Bytes(specs)) => specs.clone(),
+        _ => return None,
+    };
+    let unchanged = match req.headers().get::<IfRange>() {
+        Some(IfRange::EntityTag(tag)) => match r.generate_etag(state, req) {
+            Some(etag) => tag.strong_eq(&etag),
+            None => false,
+        },
+        Some(IfRange::Date(date)) => r.last_modified(state) == Some(*date),
+        None => true,
+    };
+    if unchanged {
+        Some(specs)
+    } else {
+        None
+    }
+}
+
+/// Halts with `206 Partial Content` for the satisfiable ranges in `specs`, or
+/// with `416 Range Not Satisfiable` if there are none. Several ranges are
+/// sent as a `multipart/byteranges` body with a part for each.
+fn byte_range_response<S>(
+    req: Request,
+    mut state: S,
+    content_type: &Mime,
+    (length, mut producer): (u64, RangeProducer<S>),
+    specs: &[ByteRangeSpec],
+) -> BoxedFuture
+where
+    S: HasAirshipState,
+{
+    let ranges = coalesce_ranges(
+        specs
+            .iter()
+            .filter_map(|spec| spec.to_satisfiable_range(length))
+            .collect(),
+    );
+    match ranges.as_slice() {
+        [] => {
+            set_response_header(
+                &mut state,
+                ContentRange(ContentRangeSpec::Bytes {
+                    range: None,
+                    instance_length: Some(length),
+                }),
+            );
+            halt(StatusCode::RangeNotSatisfiable, state)
+        }
+        [(first, last)] => {
+            set_response_header(
+                &mut state,
+                ContentRange(ContentRangeSpec::Bytes {
+                    range: Some((*first, *last)),
+                    instance_length: Some(length),
+                }),
+            );
+            set_response_header(&mut state, ContentLength(last - first + 1));
+            let body = producer(&mut state, &req, *first..last + 1);
+            set_response_body(&mut state, body);
+            halt(StatusCode::PartialContent, state)
+        }
+        _ => {
+            let boundary = multipart_boundary();
+            let parts = ranges
+                .iter()
+                .map(|&(first, last)| {
+                    let part = producer(&mut state, &req, first..last + 1);
+                    ((first, last), part)
+                })
+                .collect();
+            let body =
+                multipart_byteranges(&boundary, content_type, length, parts);
+            let multipart =
+                format!("multipart/byteranges; boundary={}", boundary);
+            if let Ok(multipart) = multipart.parse() {
+                set_response_header(&mut state, ContentType(multipart));
+            }
+            set_response_stream(&mut state, body);
+            halt(StatusCode::PartialContent, state)
+        }
+    }
+}
+
+/// The most ranges served as separate parts of one response.
+const MAX_RANGES: usize = 16;
+
+/// Merges ranges that overlap or adjoin, and answers a request for more than
+/// `MAX_RANGES` ranges with the single range covering them all, as RFC 7233
+/// section 6.1 advises. Ranges are otherwise kept in the requested order.
+fn coalesce_ranges(mut ranges: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    if ranges.len() > MAX_RANGES {
+        let first = ranges.iter().map(|&(first, _)| first).min();
+        let last = ranges.iter().map(|&(_, last)| last).max();
+        return first.into_iter().zip(last).collect();
+    }
+    let touches = |a: &(u64, u64), b: &(u64, u64)| {
+        a.0 <= b.1.saturating_add(1) && b.0 <= a.1.saturating_add(1)
+    };
+    let overlapping = ranges
+        .iter()
+        .enumerate()
+        .any(|(i, a)| ranges[i + 1..].iter().any(|b| touches(a, b)));
+    if !overlapping {
+        return ranges;
+    }
+    ranges.sort();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(previous) if touches(previous, &range) => {
+                previous.1 = previous.1.max(range.1);
+            }
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// Frames the parts of a `multipart/byteranges` body, each with the
+/// representation's `Content-Type` and its own `Content-Range`.
+fn multipart_byteranges(
+    boundary: &str,
+    content_type: &Mime,
+    length: u64,
+    parts: Vec<((u64, u64), Body)>,
+) -> ResponseBody {
+    let mut body: ResponseBody = Box::new(Body::empty());
+    for ((first, last), part) in parts {
+        let part_headers = format!(
+            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+            boundary, content_type, first, last, length
+        );
+        body = Box::new(body.chain(Body::from(part_headers)).chain(part));
+    }
+    let closing = format!("\r\n--{}--\r\n", boundary);
+    Box::new(body.chain(Body::from(closing)))
+}
+
+/// A boundary for a multipart body. It only has to be unlikely to occur in
+/// the parts, so the current time is enough to make it so.
+fn multipart_boundary() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos())
+        .unwrap_or(0);
+    format!("airship-{:x}", nanos)
+}
+
+/// Halts a `HEAD` request with `200 OK` and no body. The body produced for
+/// it is drained so that the `Content-Length` is the one a `GET` request
+/// would be sent, after any charset conversion and content coding.
+fn head_response<S>(mut state: S) -> BoxedFuture
+where
+    S: HasAirshipState + 'static,
+{
+    let body = take_response_body(&mut state);
+    Box::new(
+        body.fold(0, |length, chunk| {
+            Ok::<u64, hyper::Error>(length + chunk.len() as u64)
+        })
+        .and_then(move |length| {
+            set_response_header(&mut state, ContentLength(length));
+            halt(StatusCode::Ok, state)
+        }),
+    )
+}
+
+/// Whether the request carries the date header `H` with a value that parses
+/// as an HTTP-date. Any of the formats in RFC 7231 section 7.1.1.1 is valid:
+/// the preferred IMF-fixdate as well as the obsolete RFC 850 and asctime
+/// formats. RFC 7232 requires a header with an invalid date to be ignored.
+fn is_valid_date<H: Header>(req: &Request) -> bool {
+    match req.headers().get_raw(H::header_name()) {
+        Some(raw) => H::parse_header(raw).is_ok(),
+        None => false,
+    }
+}
+
+/// Collects the request body and hands it to `next` along with the rest of
+/// the decision context. The body size is checked against the resource's
+/// `max_body_size` as chunks arrive so that requests without a
+/// `Content-Length` header are still halted with `413 Payload Too Large`.
+/// A body that cannot be read is answered with `500 Internal Server Error`.
+fn read_request_body<R, S, F>(
+    r: R,
+    mut req: Request,
+    mut state: S,
+    next: F,
+) -> BoxedFuture
+where
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
+    F: FnOnce(R, Request, S, Vec<u8>) -> BoxedFuture + 'static,
+{
+    let max_size = r.max_body_size(&mut state);
+    let body = req.body_mut().take().unwrap_or_default();
+    let collected = body.map_err(BodyError::Hyper).fold(
+        Vec::new(),
+        move |mut acc, chunk| {
+            acc.extend_from_slice(&chunk);
+            match max_size {
+                Some(max) if acc.len() as u64 > max => Err(BodyError::TooLarge),
+                _ => Ok(acc),
+            }
+        },
+    );
+    Box::new(collected.then(move |result| match result {
+        Ok(body) => next(r, req, state, body),
+        Err(BodyError::TooLarge) => halt(StatusCode::PayloadTooLarge, state),
+        Err(BodyError::Hyper(e)) => halt_with_error(e.into(), state),
+    }))
+}
+
+/// Builds the `Location` of a resource created by a `POST` by appending its
+/// percent-encoded path segments to the request path. The path is resolved
+/// against the resource's `base_uri` when it has one.
+fn created_location(
+    base_uri: Option<Uri>,
+    req: &Request,
     path_segments: &[String],
-) -> Option<()>
+) -> String {
+    let mut location = String::new();
+    if let Some(base_uri) = base_uri {
+        if let (Some(scheme), Some(authority)) =
+            (base_uri.scheme(), base_uri.authority())
+        {
+            location.push_str(scheme);
+            location.push_str("://");
+            location.push_str(authority);
+        }
+        location.push_str(base_uri.path().trim_end_matches('/'));
+    }
+    location.push_str(req.path().trim_end_matches('/'));
+    for segment in path_segments {
+        location.push('/');
+        location.extend(utf8_percent_encode(segment, PATH_SEGMENT_ENCODE_SET));
+    }
+    if location.is_empty() {
+        location.push('/');
+    }
+    location
+}
+
+fn create<R, S>(r: &R, req: &Request, state: &mut S) -> Option<BodyAcceptor<S>>
 where
     R: Webmachine,
     S: HasAirshipState,
 {
-    let location = append_request_path(req, path_segments);
-    set_response_header(state, Location::new(location));
     let accepted = r.content_types_accepted(state);
     req.headers()
         .get::<ContentType>()
-        .and_then(|ct_hdr| map_content_media::<()>(accepted, ct_hdr))
-        .and_then(|action| {
-            action(req);
-            Some(())
-        })
+        .and_then(|ct_hdr| map_content_media::<S, ()>(accepted, ct_hdr))
 }
 
-fn process_post_action<R, S>(
+/// Sets the `Location` of a resource created by a `POST` once its body has
+/// been accepted, unless the acceptor set one itself, so that a request that
+/// is refused never points at a resource that was not created.
+fn set_created_location<R, S>(
     r: &R,
     req: &Request,
     state: &mut S,
-    pr: PostResponse,
-) -> BoxedFuture
-where
+    path_segments: &[String],
+) where
     R: Webmachine,
     S: HasAirshipState,
+{
+    if has_response_header::<Location, S>(state) {
+        return;
+    }
+    let base_uri = r.base_uri(state, req);
+    let location = created_location(base_uri, req, path_segments);
+    set_response_header(state, Location::new(location));
+}
+
+fn process_post_action<R, S>(
+    r: R,
+    req: Request,
+    mut state: S,
+    pr: PostResponse<S>,
+) -> BoxedFuture
+where
+    R: Webmachine + 'static,
+    S: HasAirshipState + 'static,
 {
     match pr {
-        PostResponse::PostCreate(ref path_segments) => {
-            match create(r, req, state, path_segments) {
-                Some(()) => p11(r, req, state),
+        PostResponse::PostCreate(path_segments) => {
+            match create(&r, &req, &mut state) {
+                Some(action) => read_request_body(
+                    r,
+                    req,
+                    state,
+                    move |r, req, mut state, body| {
+                        if let Err(error) = action(&mut state, &req, &body) {
+                            return halt_with_error(error, state);
+                        }
+                        set_created_location(
+                            &r,
+                            &req,
+                            &mut state,
+                            &path_segments,
+                        );
+                        p11(r, req, state)
+                    },
+                ),
                 None => halt(StatusCode::UnsupportedMediaType, state),
             }
         }
-        PostResponse::PostCreateRedirect(ref path_segments) => {
-            match create(r, req, state, path_segments) {
-                Some(()) => halt(StatusCode::SeeOther, state),
+        PostResponse::PostCreateRedirect(path_segments) => {
+            match create(&r, &req, &mut state) {
+                Some(action) => read_request_body(
+                    r,
+                    req,
+                    state,
+                    move |r, req, mut state, body| {
+                        if let Err(error) = action(&mut state, &req, &body) {
+                            return halt_with_error(error, state);
+                        }
+                        set_created_location(
+                            &r,
+                            &req,
+                            &mut state,
+                            &path_segments,
+                        );
+                        halt(StatusCode::SeeOther, state)
+                    },
+                ),
                 None => halt(StatusCode::UnsupportedMediaType, state),
             }
         }
         PostResponse::PostProcess(accepted) => {
-            let result = req
-                .headers()
-                .get::<ContentType>()
-                .and_then(|ct_hdr| map_content_media::<()>(accepted, ct_hdr));
+            let result =
+                req.headers().get::<ContentType>().and_then(|ct_hdr| {
+                    map_content_media::<S, ()>(accepted, ct_hdr)
+                });
             match result {
-                Some(action) => {
-                    action(req);
-                    p11(r, req, state)
-                }
+                Some(action) => read_request_body(
+                    r,
+                    req,
+                    state,
+                    move |r, req, mut state, body| {
+                        if let Err(error) = action(&mut state, &req, &body) {
+                            return halt_with_error(error, state);
+                        }
+                        p11(r, req, state)
+                    },
+                ),
                 None => halt(StatusCode::UnsupportedMediaType, state),
             }
         }
         PostResponse::PostProcessRedirect(accepted) => {
             let result =
                 req.headers().get::<ContentType>().and_then(|ct_hdr| {
-                    map_content_media::<String>(accepted, ct_hdr)
+                    map_content_media::<S, String>(accepted, ct_hdr)
                 });
             match result {
-                Some(action) => {
-                    let location = action(req);
-                    set_response_header(state, Location::new(location));
-                    halt(StatusCode::SeeOther, state)
-                }
+                Some(action) => read_request_body(
+                    r,
+                    req,
+                    state,
+                    move |_r, req, mut state, body| {
+                        let location = match action(&mut state, &req, &body) {
+                            Ok(location) => location,
+                            Err(error) => return halt_with_error(error, state),
+                        };
+                        set_response_header(
+                            &mut state,
+                            Location::new(location),
+                        );
+                        halt(StatusCode::SeeOther, state)
+                    },
+                ),
                 None => halt(StatusCode::UnsupportedMediaType, state),
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::{Cell, RefCell};
+    use std::fmt;
+    use std::io::Read;
+    use std::ops;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use flate2::read::{GzDecoder, ZlibDecoder};
+
+    use crate::resource::{BodyProducer, Webmachine};
+
+    /// A request for `path` carrying the given raw headers.
+    fn request<I>(method: Method, path: &str, headers: I) -> Request
+    where
+        I: IntoIterator<Item = (&'static str, &'static str)>,
+    {
+        let mut req = Request::new(method, path.parse().unwrap());
+        for (name, value) in headers {
+            req.headers_mut().set_raw(name, value);
+        }
+        req
+    }
+
+    /// Runs `req` through the decision graph of `resource` with an empty
+    /// request state.
+    fn run<R>(resource: R, req: Request) -> Response<ResponseBody>
+    where
+        R: Webmachine + 'static,
+    {
+        traverse(resource, req, RequestState::new()).wait().unwrap()
+    }
+
+    /// Runs `req` like `run` and collects the body the response is sent
+    /// with.
+    fn run_with_body<R>(
+        resource: R,
+        req: Request,
+    ) -> (Response<ResponseBody>, Vec<u8>)
+    where
+        R: Webmachine + 'static,
+    {
+        let sent = Rc::new(RefCell::new(None));
+        let slot = Rc::clone(&sent);
+        let mut state = RequestState::new();
+        set_body_wrapper(
+            &mut state,
+            Box::new(move |body| -> ResponseBody {
+                *slot.borrow_mut() = Some(body);
+                Box::new(futures::stream::empty())
+            }),
+        );
+        let response = traverse(resource, req, state).wait().unwrap();
+        let body = sent.borrow_mut().take().unwrap();
+        (response, body.concat2().wait().unwrap().to_vec())
+    }
+
+    /// The response header `H` as it is sent on the wire.
+    fn sent_header<H>(response: &Response<ResponseBody>) -> Option<String>
+    where
+        H: Header + fmt::Display,
+    {
+        response.headers().get::<H>().map(H::to_string)
+    }
+
+    fn accept(value: &'static str) -> Accept {
+        Accept::parse_header(&value.into()).unwrap()
+    }
+
+    fn mimes(values: &[&str]) -> Vec<Mime> {
+        values.iter().map(|value| value.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn map_accept_media_follows_rfc7231() {
+        let cases: Vec<(&str, &'static str, Vec<&str>, Option<&str>)> = vec![
+            (
+                "exact match",
+                "text/html",
+                vec!["text/html"],
+                Some("text/html"),
+            ),
+            ("no match", "application/json", vec!["text/html"], None),
+            (
+                "any type prefers server order",
+                "*/*",
+                vec!["text/html", "application/json"],
+                Some("text/html"),
+            ),
+            (
+                "exact match beats any type of equal quality",
+                "*/*, application/json",
+                vec!["text/html", "application/json"],
+                Some("application/json"),
+            ),
+            (
+                "subtype wildcard",
+                "text/*",
+                vec!["application/json", "text/plain"],
+                Some("text/plain"),
+            ),
+            (
+                "highest quality wins",
+                "text/*;q=0.5, application/json;q=0.8",
+                vec!["text/plain", "application/json"],
+                Some("application/json"),
+            ),
+            (
+                "equal quality prefers server order",
+                "text/html, application/json",
+                vec!["application/json", "text/html"],
+                Some("application/json"),
+            ),
+            (
+                "zero quality entries do not stop matching",
+                "text/plain;q=0, application/json",
+                vec!["application/json"],
+                Some("application/json"),
+            ),
+            (
+                "zero quality excludes a type matched by a wildcard",
+                "*/*, text/html;q=0",
+                vec!["text/html", "application/json"],
+                Some("application/json"),
+            ),
+            (
+                "zero quality subtype wildcard",
+                "text/*;q=0, */*;q=0.1",
+                vec!["text/plain", "image/png"],
+                Some("image/png"),
+            ),
+            (
+                "most specific range sets the quality",
+                "text/*;q=0.9, text/plain;q=0.1",
+                vec!["text/plain", "text/csv"],
+                Some("text/csv"),
+            ),
+            (
+                "only zero quality matches",
+                "text/html;q=0, application/json;q=0",
+                vec!["text/html", "application/json"],
+                None,
+            ),
+            (
+                "range parameters must match",
+                "application/vnd.api+json;version=2",
+                vec![
+                    "application/vnd.api+json; version=1",
+                    "application/vnd.api+json; version=2",
+                ],
+                Some("application/vnd.api+json; version=2"),
+            ),
+            (
+                "range parameters with no match",
+                "application/vnd.api+json;version=3",
+                vec![
+                    "application/vnd.api+json; version=1",
+                    "application/vnd.api+json; version=2",
+                ],
+                None,
+            ),
+            (
+                "range without parameters matches a type with them",
+                "application/json",
+                vec!["application/json; charset=utf-8"],
+                Some("application/json; charset=utf-8"),
+            ),
+            (
+                "range with parameters is more specific",
+                "text/plain;format=flowed;q=0, text/plain",
+                vec!["text/plain; format=flowed", "text/plain"],
+                Some("text/plain"),
+            ),
+            (
+                "charset values ignore case",
+                "text/html;charset=UTF-8",
+                vec!["text/html; charset=utf-8"],
+                Some("text/html; charset=utf-8"),
+            ),
+            (
+                "suffix is part of the subtype",
+                "application/json",
+                vec!["application/vnd.api+json"],
+                None,
+            ),
+        ];
+
+        for (name, header, provided, expected) in cases {
+            let matched = map_accept_media(&mimes(&provided), &accept(header));
+            let expected = expected.map(|value| value.parse::<Mime>().unwrap());
+            assert_eq!(matched, expected, "{}", name);
+        }
+    }
+
+    /// A representation long enough for every coding to shrink it.
+    const REPRESENTATION: &str = "\
+        The quick brown fox jumps over the lazy dog. \
+        The quick brown fox jumps over the lazy dog. \
+        The quick brown fox jumps over the lazy dog.";
+
+    /// Serves `REPRESENTATION` as `text/plain` with the given codings, either
+    /// in full or streamed a word at a time. `produced` records whether the
+    /// body producer was called and `polled` whether a streamed body was
+    /// read.
+    #[derive(Default)]
+    struct RepresentationResource {
+        encodings: Vec<Encoding>,
+        content_length: Option<u64>,
+        streamed: bool,
+        produced: Rc<Cell<bool>>,
+        polled: Rc<Cell<bool>>,
+    }
+
+    impl Webmachine for RepresentationResource {
+        fn content_length<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+            _req: &Request,
+        ) -> Option<u64> {
+            self.content_length
+        }
+
+        fn content_types_provided<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<(Mime, BodyProducer<S>)> {
+            let streamed = self.streamed;
+            let produced = Rc::clone(&self.produced);
+            let polled = Rc::clone(&self.polled);
+            let producer = move |_state: &mut S, _req: &Request| {
+                produced.set(true);
+                if !streamed {
+                    return Ok(ProvidedBody::from(REPRESENTATION));
+                }
+                let words = REPRESENTATION.split_inclusive(' ');
+                Ok(ProvidedBody::stream(futures::stream::iter_ok(words).map(
+                    move |word| {
+                        polled.set(true);
+                        Chunk::from(word)
+                    },
+                )))
+            };
+            vec![(mime::TEXT_PLAIN, Box::new(producer))]
+        }
+
+        fn encodings_provided<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<Encoding> {
+            if self.encodings.is_empty() {
+                vec![Encoding::Identity]
+            } else {
+                self.encodings.clone()
+            }
+        }
+    }
+
+    /// Reverses the content coding named by a `Content-Encoding` header.
+    fn decode(content_encoding: Option<&str>, body: &[u8]) -> Vec<u8> {
+        let mut decoded = Vec::new();
+        let mut decoder: Box<dyn Read> = match content_encoding {
+            None => return body.to_vec(),
+            Some("gzip") => Box::new(GzDecoder::new(body)),
+            Some("deflate") => Box::new(ZlibDecoder::new(body)),
+            Some("br") => Box::new(brotli::Decompressor::new(body, 4096)),
+            Some(other) => panic!("unexpected coding {}", other),
+        };
+        decoder.read_to_end(&mut decoded).unwrap();
+        decoded
+    }
+
+    struct EncodingCase {
+        name: &'static str,
+        provided: Vec<Encoding>,
+        accept_encoding: Option<&'static str>,
+        status: StatusCode,
+        content_encoding: Option<&'static str>,
+        vary: Option<&'static str>,
+    }
+
+    #[test]
+    fn accept_encoding_selects_and_applies_content_coding() {
+        let all = vec![
+            Encoding::Identity,
+            Encoding::Gzip,
+            Encoding::Deflate,
+            Encoding::Brotli,
+        ];
+        let unsupported = vec![Encoding::Identity, Encoding::Compress];
+        let cases = vec![
+            EncodingCase {
+                name: "no header",
+                provided: all.clone(),
+                accept_encoding: None,
+                status: StatusCode::Ok,
+                content_encoding: None,
+                vary: Some("Accept-Encoding"),
+            },
+            EncodingCase {
+                name: "gzip",
+                provided: all.clone(),
+                accept_encoding: Some("gzip"),
+                status: StatusCode::Ok,
+                content_encoding: Some("gzip"),
+                vary: Some("Accept-Encoding"),
+            },
+            EncodingCase {
+                name: "deflate",
+                provided: all.clone(),
+                accept_encoding: Some("deflate"),
+                status: StatusCode::Ok,
+                content_encoding: Some("deflate"),
+                vary: Some("Accept-Encoding"),
+            },
+            EncodingCase {
+                name: "brotli",
+                provided: all.clone(),
+                accept_encoding: Some("br"),
+                status: StatusCode::Ok,
+                content_encoding: Some("br"),
+                vary: Some("Accept-Encoding"),
+            },
+            EncodingCase {
+                name: "highest quality wins",
+                provided: all.clone(),
+                accept_encoding: Some("gzip;q=0.5, br"),
+                status: StatusCode::Ok,
+                content_encoding: Some("br"),
+                vary: Some("Accept-Encoding"),
+            },
+            EncodingCase {
+                name: "identity refused",
+                provided: all.clone(),
+                accept_encoding: Some("gzip, identity;q=0"),
+                status: StatusCode::Ok,
+                content_encoding: Some("gzip"),
+                vary: Some("Accept-Encoding"),
+            },
+            EncodingCase {
+                name: "only identity offered and refused",
+                provided: vec![Encoding::Identity],
+                accept_encoding: Some("identity;q=0"),
+                status: StatusCode::NotAcceptable,
+                content_encoding: None,
+                vary: None,
+            },
+            EncodingCase {
+                name: "any coding refused",
+                provided: all.clone(),
+                accept_encoding: Some("*;q=0"),
+                status: StatusCode::NotAcceptable,
+                content_encoding: None,
+                vary: None,
+            },
+            EncodingCase {
+                name: "unsupported coding is not applied",
+                provided: unsupported.clone(),
+                accept_encoding: Some("compress"),
+                status: StatusCode::Ok,
+                content_encoding: None,
+                vary: None,
+            },
+            EncodingCase {
+                name: "unsupported coding is not acceptable",
+                provided: unsupported,
+                accept_encoding: Some("compress, identity;q=0"),
+                status: StatusCode::NotAcceptable,
+                content_encoding: None,
+                vary: None,
+            },
+        ];
+
+        for case in cases {
+            let headers =
+                case.accept_encoding.map(|value| ("Accept-Encoding", value));
+            let req = request(Method::Get, "/", headers);
+            let resource = RepresentationResource {
+                encodings: case.provided,
+                ..RepresentationResource::default()
+            };
+            let (response, body) = run_with_body(resource, req);
+            let name = case.name;
+            assert_eq!(response.status(), case.status, "{}", name);
+            let content_encoding = sent_header::<ContentEncoding>(&response);
+            assert_eq!(
+                content_encoding.as_deref(),
+                case.content_encoding,
+                "{}",
+                name
+            );
+            let vary = sent_header::<Vary>(&response);
+            assert_eq!(vary.as_deref(), case.vary, "{}", name);
+            if case.status == StatusCode::Ok {
+                let decoded = decode(case.content_encoding, &body);
+                assert_eq!(decoded, REPRESENTATION.as_bytes(), "{}", name);
+                if case.content_encoding.is_some() {
+                    assert!(body.len() < REPRESENTATION.len(), "{}", name);
+                }
+            }
+        }
+    }
+
+    fn accept_charset(value: &'static str) -> AcceptCharset {
+        AcceptCharset::parse_header(&value.into()).unwrap()
+    }
+
+    #[test]
+    fn map_accept_charset_follows_rfc7231() {
+        let cases = vec![
+            ("exact match", "iso-8859-1", Some(Charset::Iso_8859_1)),
+            ("names ignore case", "ISO-8859-1", Some(Charset::Iso_8859_1)),
+            (
+                "any charset prefers server order",
+                "*",
+                Some(Charset::Ext(String::from("utf-8"))),
+            ),
+            (
+                "highest quality wins",
+                "utf-8;q=0.5, iso-8859-1;q=0.8",
+                Some(Charset::Iso_8859_1),
+            ),
+            (
+                "explicit quality beats any charset",
+                "utf-8;q=0, *",
+                Some(Charset::Iso_8859_1),
+            ),
+            ("unlisted charsets are not acceptable", "koi8-r", None),
+            ("zero quality", "utf-8;q=0, iso-8859-1;q=0", None),
+        ];
+        let provided =
+            vec![Charset::Ext(String::from("utf-8")), Charset::Iso_8859_1];
+
+        for (name, header, expected) in cases {
+            let matched =
+                map_accept_charset(&provided, &accept_charset(header));
+            assert_eq!(matched, expected, "{}", name);
+        }
+    }
+
+    struct CharsetResource;
+
+    impl Webmachine for CharsetResource {
+        fn charsets_provided<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<(Charset, Option<CharsetConverter>)> {
+            vec![
+                (Charset::Ext(String::from("utf-8")), None),
+                (Charset::Iso_8859_1, Some(Box::new(|chunk| chunk.to_vec()))),
+            ]
+        }
+    }
+
+    #[test]
+    fn accept_charset_selects_content_type_charset() {
+        let cases = vec![
+            (
+                "no header",
+                None,
+                StatusCode::Ok,
+                Some("text/plain; charset=utf-8"),
+            ),
+            (
+                "provided charset",
+                Some("iso-8859-1"),
+                StatusCode::Ok,
+                Some("text/plain; charset=iso-8859-1"),
+            ),
+            (
+                "refused first charset",
+                Some("utf-8;q=0, *"),
+                StatusCode::Ok,
+                Some("text/plain; charset=iso-8859-1"),
+            ),
+            (
+                "nothing acceptable",
+                Some("koi8-r"),
+                StatusCode::NotAcceptable,
+                None,
+            ),
+        ];
+
+        for (name, header, status, content_type) in cases {
+            let headers = header.map(|value| ("Accept-Charset", value));
+            let req = request(Method::Get, "/", headers);
+            let response = run(CharsetResource, req);
+            assert_eq!(response.status(), status, "{}", name);
+            let actual = sent_header::<ContentType>(&response);
+            assert_eq!(actual.as_deref(), content_type, "{}", name);
+        }
+    }
+
+    fn languages(values: &[&str]) -> Vec<LanguageTag> {
+        values.iter().map(|value| value.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn map_accept_language_follows_rfc4647() {
+        let cases = vec![
+            ("exact match", "fr", Some("fr")),
+            ("tags ignore case", "EN-gb", Some("en-GB")),
+            (
+                "prefix matches longer tags in server order",
+                "en",
+                Some("en"),
+            ),
+            ("lookup removes subtags", "en-US", Some("en")),
+            ("highest quality wins", "de, fr;q=0.5", Some("fr")),
+            ("zero quality refuses a tag", "en;q=0, en-GB", Some("en-GB")),
+            ("lookup honours refusals", "en-US, en;q=0", None),
+            ("any language prefers server order", "*", Some("en")),
+            ("any language as a fallback", "de, *;q=0.1", Some("en")),
+            (
+                "explicit quality beats any language",
+                "en;q=0, *",
+                Some("fr"),
+            ),
+            ("any language refused", "*;q=0", None),
+            ("nothing acceptable", "de", None),
+            ("no parsable ranges", ",", Some("en")),
+        ];
+        let provided = languages(&["en", "en-GB", "fr"]);
+
+        for (name, header, expected) in cases {
+            let ranges = accept_language_ranges(&header.into());
+            let matched = map_accept_language(&provided, &ranges);
+            let expected = expected.map(|tag| languages(&[tag]).remove(0));
+            assert_eq!(matched, expected, "{}", name);
+        }
+    }
+
+    struct LanguageResource;
+
+    impl Webmachine for LanguageResource {
+        fn languages_provided<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<LanguageTag> {
+            languages(&["en", "fr"])
+        }
+    }
+
+    #[test]
+    fn accept_language_selects_content_language() {
+        let cases = vec![
+            ("no header", None, StatusCode::Ok, Some("en")),
+            ("provided language", Some("fr"), StatusCode::Ok, Some("fr")),
+            ("lookup", Some("fr-CA"), StatusCode::Ok, Some("fr")),
+            (
+                "any language as a fallback",
+                Some("de, *;q=0.1"),
+                StatusCode::Ok,
+                Some("en"),
+            ),
+            (
+                "any language refused",
+                Some("*;q=0"),
+                StatusCode::NotAcceptable,
+                None,
+            ),
+            (
+                "nothing acceptable",
+                Some("de"),
+                StatusCode::NotAcceptable,
+                None,
+            ),
+        ];
+
+        for (name, header, status, content_language) in cases {
+            let headers = header.map(|value| ("Accept-Language", value));
+            let req = request(Method::Get, "/", headers);
+            let response = run(LanguageResource, req);
+            assert_eq!(response.status(), status, "{}", name);
+            let actual = sent_header::<ContentLanguage>(&response);
+            assert_eq!(actual.as_deref(), content_language, "{}", name);
+        }
+    }
+
+    /// Offers the given choices in each negotiated dimension, as well as the
+    /// validators and body size limit that the conditional and error cases
+    /// need.
+    #[derive(Default)]
+    struct VaryingResource {
+        content_types: Vec<&'static str>,
+        languages: Vec<&'static str>,
+        charsets: Vec<&'static str>,
+        encodings: Vec<Encoding>,
+        variances: Vec<&'static str>,
+        missing: bool,
+    }
+
+    impl Webmachine for VaryingResource {
+        fn allowed_methods<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<Method> {
+            vec![Method::Get, Method::Put]
+        }
+
+        fn charsets_provided<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<(Charset, Option<CharsetConverter>)> {
+            let charsets = self.charsets.iter();
+            charsets.map(|name| (name.parse().unwrap(), None)).collect()
+        }
+
+        fn content_types_accepted<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<(Mime, BodyAcceptor<S>)> {
+            vec![(mime::TEXT_PLAIN, Box::new(|_state, _req, _body| Ok(())))]
+        }
+
+        fn content_types_provided<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<(Mime, BodyProducer<S>)> {
+            let content_types = self.content_types.iter();
+            content_types
+                .map(|content_type| -> (Mime, BodyProducer<S>) {
+                    let producer = |_state: &mut S, _req: &Request| {
+                        Ok(ProvidedBody::from("representation"))
+                    };
+                    (content_type.parse().unwrap(), Box::new(producer))
+                })
+                .collect()
+        }
+
+        fn encodings_provided<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<Encoding> {
+            self.encodings.clone()
+        }
+
+        fn generate_etag<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+            _req: &Request,
+        ) -> Option<EntityTag> {
+            Some(EntityTag::strong(String::from("v1")))
+        }
+
+        fn languages_provided<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<LanguageTag> {
+            languages(&self.languages)
+        }
+
+        fn max_body_size<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Option<u64> {
+            Some(4)
+        }
+
+        fn resource_exists<S: HasAirshipState>(&self, _state: &mut S) -> bool {
+            !self.missing
+        }
+
+        fn variances<S: HasAirshipState>(&self, _state: &mut S) -> Vec<String> {
+            self.variances
+                .iter()
+                .map(|header| header.to_string())
+                .collect()
+        }
+    }
+
+    struct VaryCase {
+        name: &'static str,
+        resource: VaryingResource,
+        method: Method,
+        headers: Vec<(&'static str, &'static str)>,
+        body: &'static str,
+        status: StatusCode,
+        vary: Option<&'static str>,
+    }
+
+    impl Default for VaryCase {
+        fn default() -> Self {
+            VaryCase {
+                name: "",
+                resource: VaryingResource::default(),
+                method: Method::Get,
+                headers: vec![],
+                body: "",
+                status: StatusCode::Ok,
+                vary: None,
+            }
+        }
+    }
+
+    /// One choice in each dimension, which leaves nothing to vary by.
+    fn single_choices() -> VaryingResource {
+        VaryingResource {
+            content_types: vec!["text/plain"],
+            languages: vec!["en"],
+            charsets: vec!["utf-8"],
+            encodings: vec![Encoding::Identity],
+            ..VaryingResource::default()
+        }
+    }
+
+    /// Several choices in each dimension.
+    fn several_choices() -> VaryingResource {
+        VaryingResource {
+            content_types: vec!["text/plain", "text/html"],
+            languages: vec!["en", "fr"],
+            charsets: vec!["utf-8", "iso-8859-1"],
+            encodings: vec![Encoding::Identity, Encoding::Gzip],
+            ..VaryingResource::default()
+        }
+    }
+
+    #[test]
+    fn vary_lists_the_negotiated_dimensions_and_variances() {
+        let all = "Accept, Accept-Language, Accept-Charset, Accept-Encoding";
+        let cases = vec![
+            VaryCase {
+                name: "one choice in each dimension",
+                resource: single_choices(),
+                ..VaryCase::default()
+            },
+            VaryCase {
+                name: "several media types",
+                resource: VaryingResource {
+                    content_types: vec!["text/plain", "text/html"],
+                    ..single_choices()
+                },
+                vary: Some("Accept"),
+                ..VaryCase::default()
+            },
+            VaryCase {
+                name: "several choices in each dimension",
+                resource: several_choices(),
+                vary: Some(all),
+                ..VaryCase::default()
+            },
+            VaryCase {
+                name: "unsupported codings are not a choice",
+                resource: VaryingResource {
+                    encodings: vec![Encoding::Identity, Encoding::Compress],
+                    ..single_choices()
+                },
+                ..VaryCase::default()
+            },
+            VaryCase {
+                name: "variances alone",
+                resource: VaryingResource {
+                    variances: vec!["Cookie"],
+                    ..single_choices()
+                },
+                vary: Some("Cookie"),
+                ..VaryCase::default()
+            },
+            VaryCase {
+                name: "variances follow the negotiated dimensions",
+                resource: VaryingResource {
+                    content_types: vec!["text/plain", "text/html"],
+                    variances: vec!["Cookie", "Authorization"],
+                    ..single_choices()
+                },
+                vary: Some("Accept, Cookie, Authorization"),
+                ..VaryCase::default()
+            },
+            VaryCase {
+                name: "not modified",
+                resource: several_choices(),
+                headers: vec![("If-None-Match", "\"v1\"")],
+                status: StatusCode::NotModified,
+                vary: Some(all),
+                ..VaryCase::default()
+            },
+            VaryCase {
+                name: "not found",
+                resource: VaryingResource {
+                    missing: true,
+                    ..several_choices()
+                },
+                status: StatusCode::NotFound,
+                ..VaryCase::default()
+            },
+            VaryCase {
+                name: "payload too large",
+                resource: several_choices(),
+                method: Method::Put,
+                headers: vec![("Content-Type", "text/plain")],
+                body: "too large",
+                status: StatusCode::PayloadTooLarge,
+                ..VaryCase::default()
+            },
+        ];
+
+        for case in cases {
+            let mut req = request(case.method, "/", case.headers);
+            req.set_body(case.body);
+            let response = run(case.resource, req);
+            assert_eq!(response.status(), case.status, "{}", case.name);
+            let vary = sent_header::<Vary>(&response);
+            assert_eq!(vary.as_deref(), case.vary, "{}", case.name);
+        }
+    }
+
+    #[test]
+    fn map_content_media_matches_media_ranges() {
+        let cases = vec![
+            (
+                "exact type beats suffix range",
+                "application/json",
+                Some("json"),
+            ),
+            ("suffix range", "application/vnd.api+json", Some("+json")),
+            (
+                "parameters are not required",
+                "application/json; charset=utf-8",
+                Some("json"),
+            ),
+            ("subtype wildcard", "text/plain", Some("text")),
+            (
+                "parameters that match",
+                "text/csv; header=present",
+                Some("csv with header"),
+            ),
+            (
+                "parameters that differ fall back to a wider range",
+                "text/csv; header=absent",
+                Some("text"),
+            ),
+            ("other suffix", "application/vnd.api+xml", None),
+            ("no match", "application/xml", None),
+        ];
+
+        for (name, content_type, expected) in cases {
+            let accepted: Vec<(Mime, BodyAcceptor<(), &str>)> = vec![
+                ("application/json", "json"),
+                ("application/*+json", "+json"),
+                ("text/*", "text"),
+                ("text/csv; header=present", "csv with header"),
+            ]
+            .into_iter()
+            .map(|(range, label)| -> (Mime, BodyAcceptor<(), &str>) {
+                (range.parse().unwrap(), Box::new(move |_, _, _| Ok(label)))
+            })
+            .collect();
+            let content_type = ContentType(content_type.parse().unwrap());
+            let req = Request::new(Method::Post, "/".parse().unwrap());
+            let matched = map_content_media(accepted, &content_type)
+                .map(|action| action(&mut (), &req, &[]).unwrap());
+            assert_eq!(matched, expected, "{}", name);
+        }
+    }
+
+    /// Knows WebDAV's `PROPFIND` and `REPORT` methods but has only
+    /// implemented `PROPFIND`.
+    struct DavResource;
+
+    fn dav_method(name: &str) -> Method {
+        Method::Extension(String::from(name))
+    }
+
+    impl Webmachine for DavResource {
+        fn allowed_methods<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<Method> {
+            vec![Method::Get, dav_method("PROPFIND"), dav_method("REPORT")]
+        }
+
+        fn implemented<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+            req: &Request,
+        ) -> bool {
+            *req.method() != dav_method("REPORT")
+        }
+
+        fn known_methods<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<Method> {
+            vec![Method::Get, dav_method("PROPFIND"), dav_method("REPORT")]
+        }
+    }
+
+    #[test]
+    fn b12_checks_known_and_implemented_methods() {
+        let cases = vec![
+            ("known method", Method::Get, StatusCode::Ok),
+            (
+                "known extension method",
+                dav_method("PROPFIND"),
+                StatusCode::Ok,
+            ),
+            (
+                "unimplemented extension method",
+                dav_method("REPORT"),
+                StatusCode::NotImplemented,
+            ),
+            (
+                "unknown extension method",
+                dav_method("MKCOL"),
+                StatusCode::NotImplemented,
+            ),
+            ("unknown method", Method::Delete, StatusCode::NotImplemented),
+        ];
+
+        for (name, method, status) in cases {
+            let response = run(DavResource, request(method, "/", []));
+            assert_eq!(response.status(), status, "{}", name);
+            let trace = sent_header::<AirshipTrace>(&response).unwrap();
+            let past_b12 = trace.contains("b12,b11");
+            assert_eq!(
+                past_b12,
+                status != StatusCode::NotImplemented,
+                "{}",
+                name
+            );
+        }
+    }
+
+    struct DatedResource;
+
+    impl Webmachine for DatedResource {
+        fn last_modified<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Option<HttpDate> {
+            "Sun, 06 Nov 1994 08:49:37 GMT".parse().ok()
+        }
+    }
+
+    fn decision_trace(req: Request) -> String {
+        let response = run(DatedResource, req);
+        response.headers().get::<AirshipTrace>().unwrap().0.clone()
+    }
+
+    #[test]
+    fn is_valid_date_accepts_http_date_formats() {
+        let cases = vec![
+            ("IMF-fixdate", "Sun, 06 Nov 1994 08:49:37 GMT", true),
+            ("obsolete RFC 850", "Sunday, 06-Nov-94 08:49:37 GMT", true),
+            ("obsolete asctime", "Sun Nov  6 08:49:37 1994", true),
+            ("missing time zone", "Sun, 06 Nov 1994 08:49:37", false),
+            ("ISO 8601", "1994-11-06T08:49:37Z", false),
+            ("garbage", "yesterday", false),
+            ("empty", "", false),
+        ];
+
+        for (name, value, expected) in cases {
+            let req = request(Method::Get, "/", [("If-Modified-Since", value)]);
+            assert_eq!(
+                is_valid_date::<IfModifiedSince>(&req),
+                expected,
+                "If-Modified-Since {}",
+                name
+            );
+            let req =
+                request(Method::Get, "/", [("If-Unmodified-Since", value)]);
+            assert_eq!(
+                is_valid_date::<IfUnmodifiedSince>(&req),
+                expected,
+                "If-Unmodified-Since {}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_if_unmodified_since_is_ignored() {
+        let req =
+            request(Method::Get, "/", [("If-Unmodified-Since", "not a date")]);
+        assert!(decision_trace(req).contains("h11,i12"));
+    }
+
+    #[test]
+    fn invalid_if_modified_since_is_ignored() {
+        let req =
+            request(Method::Get, "/", [("If-Modified-Since", "not a date")]);
+        assert!(decision_trace(req).contains("l14,m16"));
+    }
+
+    #[test]
+    fn obsolete_if_modified_since_is_honoured() {
+        let req = request(
+            Method::Get,
+            "/",
+            [("If-Modified-Since", "Sunday, 06-Nov-94 08:49:37 GMT")],
+        );
+        let response = run(DatedResource, req);
+        assert_eq!(response.status(), StatusCode::NotModified);
+    }
+
+    struct TaggedResource(EntityTag);
+
+    impl Webmachine for TaggedResource {
+        fn allowed_methods<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<Method> {
+            vec![Method::Get, Method::Put]
+        }
+
+        fn content_types_accepted<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<(Mime, BodyAcceptor<S>)> {
+            vec![(mime::TEXT_PLAIN, Box::new(|_state, _req, _body| Ok(())))]
+        }
+
+        fn generate_etag<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+            _req: &Request,
+        ) -> Option<EntityTag> {
+            Some(self.0.clone())
+        }
+    }
+
+    struct ConditionalCase {
+        name: &'static str,
+        method: Method,
+        etag: EntityTag,
+        header: &'static str,
+        value: &'static str,
+        status: StatusCode,
+    }
+
+    #[test]
+    fn entity_tags_are_compared_per_rfc7232() {
+        let strong = EntityTag::strong(String::from("v1"));
+        let weak = EntityTag::weak(String::from("v1"));
+        let cases = vec![
+            ConditionalCase {
+                name: "if-match strong tag",
+                method: Method::Get,
+                etag: strong.clone(),
+                header: "If-Match",
+                value: "\"v1\"",
+                status: StatusCode::Ok,
+            },
+            ConditionalCase {
+                name: "if-match any listed tag",
+                method: Method::Get,
+                etag: strong.clone(),
+                header: "If-Match",
+                value: "\"v0\", \"v1\"",
+                status: StatusCode::Ok,
+            },
+            ConditionalCase {
+                name: "if-match weak request tag",
+                method: Method::Get,
+                etag: strong.clone(),
+                header: "If-Match",
+                value: "W/\"v1\"",
+                status: StatusCode::PreconditionFailed,
+            },
+            ConditionalCase {
+                name: "if-match weak resource tag",
+                method: Method::Get,
+                etag: weak.clone(),
+                header: "If-Match",
+                value: "\"v1\"",
+                status: StatusCode::PreconditionFailed,
+            },
+            ConditionalCase {
+                name: "if-match other tag",
+                method: Method::Put,
+                etag: strong.clone(),
+                header: "If-Match",
+                value: "\"v0\"",
+                status: StatusCode::PreconditionFailed,
+            },
+            ConditionalCase {
+                name: "if-none-match strong tag",
+                method: Method::Get,
+                etag: strong.clone(),
+                header: "If-None-Match",
+                value: "\"v1\"",
+                status: StatusCode::NotModified,
+            },
+            ConditionalCase {
+                name: "if-none-match weak request tag",
+                method: Method::Get,
+                etag: strong.clone(),
+                header: "If-None-Match",
+                value: "W/\"v1\"",
+                status: StatusCode::NotModified,
+            },
+            ConditionalCase {
+                name: "if-none-match weak resource tag",
+                method: Method::Get,
+                etag: weak.clone(),
+                header: "If-None-Match",
+                value: "\"v1\"",
+                status: StatusCode::NotModified,
+            },
+            ConditionalCase {
+                name: "if-none-match other tag",
+                method: Method::Get,
+                etag: strong.clone(),
+                header: "If-None-Match",
+                value: "W/\"v0\"",
+                status: StatusCode::Ok,
+            },
+            ConditionalCase {
+                name: "if-none-match weak tag on put",
+                method: Method::Put,
+                etag: strong.clone(),
+                header: "If-None-Match",
+                value: "W/\"v1\"",
+                status: StatusCode::PreconditionFailed,
+            },
+        ];
+
+        for case in cases {
+            let headers =
+                [(case.header, case.value), ("Content-Type", "text/plain")];
+            let req = request(case.method, "/", headers);
+            let response = run(TaggedResource(case.etag), req);
+            assert_eq!(response.status(), case.status, "{}", case.name);
+        }
+    }
+
+    struct CachedResource;
+
+    impl Webmachine for CachedResource {
+        fn cache_control<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<CacheDirective> {
+            vec![CacheDirective::Public, CacheDirective::MaxAge(60)]
+        }
+
+        fn expires<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Option<HttpDate> {
+            "Mon, 07 Nov 1994 08:49:37 GMT".parse().ok()
+        }
+
+        fn generate_etag<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+            _req: &Request,
+        ) -> Option<EntityTag> {
+            Some(EntityTag::strong(String::from("v1")))
+        }
+
+        fn last_modified<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Option<HttpDate> {
+            "Sun, 06 Nov 1994 08:49:37 GMT".parse().ok()
+        }
+    }
+
+    #[test]
+    fn cache_headers_are_sent_on_200_and_304() {
+        let cases = vec![
+            ("unconditional", None, StatusCode::Ok),
+            (
+                "if-none-match",
+                Some(("If-None-Match", "\"v1\"")),
+                StatusCode::NotModified,
+            ),
+            (
+                "if-modified-since",
+                Some(("If-Modified-Since", "Sun, 06 Nov 1994 08:49:37 GMT")),
+                StatusCode::NotModified,
+            ),
+        ];
+
+        for (name, header, status) in cases {
+            let req = request(Method::Get, "/", header);
+            let response = run(CachedResource, req);
+            let headers = response.headers();
+            assert_eq!(response.status(), status, "{}", name);
+            assert_eq!(
+                headers.get::<CacheControl>(),
+                Some(&CacheControl(vec![
+                    CacheDirective::Public,
+                    CacheDirective::MaxAge(60),
+                ])),
+                "{}",
+                name
+            );
+            assert_eq!(
+                headers.get::<Expires>().map(|expires| expires.to_string()),
+                Some(String::from("Mon, 07 Nov 1994 08:49:37 GMT")),
+                "{}",
+                name
+            );
+            assert!(headers.has::<ETag>(), "{}", name);
+            assert!(headers.has::<LastModified>(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn cache_headers_are_not_sent_by_default() {
+        let response = run(DatedResource, request(Method::Get, "/", []));
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert!(!response.headers().has::<CacheControl>());
+        assert!(!response.headers().has::<Expires>());
+    }
+
+    /// The headers of `response` other than `Content-Length`, sorted by name.
+    fn headers_without_length(
+        response: &Response<ResponseBody>,
+    ) -> Vec<(String, String)> {
+        let mut headers = response
+            .headers()
+            .iter()
+            .filter(|header| !header.is::<ContentLength>())
+            .map(|header| (header.name().to_string(), header.value_string()))
+            .collect::<Vec<_>>();
+        headers.sort();
+        headers
+    }
+
+    #[test]
+    fn head_is_sent_the_get_headers_and_length() {
+        let length = REPRESENTATION.len() as u64;
+        let cases = vec![
+            ("measured", None, None, true),
+            ("content coded", Some("gzip"), None, true),
+            ("content_length callback", None, Some(length), false),
+            (
+                "content_length ignored when coded",
+                Some("gzip"),
+                Some(1),
+                true,
+            ),
+        ];
+
+        for (name, accept_encoding, content_length, produced) in cases {
+            let headers =
+                accept_encoding.map(|value| ("Accept-Encoding", value));
+            let resource = || RepresentationResource {
+                encodings: vec![Encoding::Identity, Encoding::Gzip],
+                content_length,
+                ..RepresentationResource::default()
+            };
+            let req = request(Method::Get, "/", headers);
+            let (get, get_body) = run_with_body(resource(), req);
+            let head_resource = resource();
+            let head_produced = Rc::clone(&head_resource.produced);
+            let req = request(Method::Head, "/", headers);
+            let (head, head_body) = run_with_body(head_resource, req);
+            assert_eq!(head.status(), StatusCode::Ok, "{}", name);
+            assert!(head_body.is_empty(), "{}", name);
+            assert_eq!(
+                head.headers().get::<ContentLength>(),
+                Some(&ContentLength(get_body.len() as u64)),
+                "{}",
+                name
+            );
+            assert_eq!(
+                headers_without_length(&head),
+                headers_without_length(&get),
+                "{}",
+                name
+            );
+            assert_eq!(head_produced.get(), produced, "{}", name);
+        }
+    }
+
+    #[test]
+    fn head_with_streamed_body_is_not_measured() {
+        let resource = RepresentationResource {
+            streamed: true,
+            ..RepresentationResource::default()
+        };
+        let produced = Rc::clone(&resource.produced);
+        let polled = Rc::clone(&resource.polled);
+        let (response, body) =
+            run_with_body(resource, request(Method::Head, "/", []));
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert!(body.is_empty());
+        assert!(!response.headers().has::<ContentLength>());
+        assert_eq!(
+            sent_header::<ContentType>(&response).as_deref(),
+            Some("text/plain")
+        );
+        assert!(produced.get());
+        assert!(!polled.get());
+    }
+
+    const ACCENTED: &str = "crème brûlée à la carte";
+
+    /// Transcodes UTF-8 text made of Latin-1 characters into ISO-8859-1.
+    fn latin1(utf8: &[u8]) -> Vec<u8> {
+        let text = std::str::from_utf8(utf8).unwrap();
+        text.chars().map(|c| c as u8).collect()
+    }
+
+    /// Streams `ACCENTED` a word at a time in ISO-8859-1, with every content
+    /// coding. `converted` records each chunk handed to the converter.
+    #[derive(Default)]
+    struct Latin1Resource {
+        converted: Rc<RefCell<Vec<Vec<u8>>>>,
+    }
+
+    impl Webmachine for Latin1Resource {
+        fn charsets_provided<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<(Charset, Option<CharsetConverter>)> {
+            let converted = Rc::clone(&self.converted);
+            let converter = move |chunk: &[u8]| {
+                converted.borrow_mut().push(chunk.to_vec());
+                latin1(chunk)
+            };
+            vec![(Charset::Iso_8859_1, Some(Box::new(converter)))]
+        }
+
+        fn content_types_provided<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<(Mime, BodyProducer<S>)> {
+            let producer = |_state: &mut S, _req: &Request| {
+                let words = ACCENTED.split_inclusive(' ').map(Chunk::from);
+                Ok(ProvidedBody::stream(futures::stream::iter_ok(words)))
+            };
+            vec![(mime::TEXT_PLAIN, Box::new(producer))]
+        }
+
+        fn encodings_provided<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<Encoding> {
+            vec![
+                Encoding::Identity,
+                Encoding::Gzip,
+                Encoding::Deflate,
+                Encoding::Brotli,
+            ]
+        }
+    }
+
+    #[test]
+    fn streamed_body_is_converted_and_encoded_chunk_by_chunk() {
+        let words: Vec<Vec<u8>> = ACCENTED
+            .split_inclusive(' ')
+            .map(|word| word.as_bytes().to_vec())
+            .collect();
+        for coding in vec!["identity", "gzip", "deflate", "br"] {
+            let headers = [
+                ("Accept-Charset", "iso-8859-1"),
+                ("Accept-Encoding", coding),
+            ];
+            let resource = Latin1Resource::default();
+            let converted = Rc::clone(&resource.converted);
+            let req = request(Method::Get, "/", headers);
+            let (response, body) = run_with_body(resource, req);
+            assert_eq!(response.status(), StatusCode::Ok, "{}", coding);
+            assert!(!response.headers().has::<ContentLength>(), "{}", coding);
+            assert_eq!(
+                sent_header::<ContentType>(&response).as_deref(),
+                Some("text/plain; charset=iso-8859-1"),
+                "{}",
+                coding
+            );
+            let content_encoding = sent_header::<ContentEncoding>(&response);
+            let expected = Some(coding).filter(|&coding| coding != "identity");
+            assert_eq!(content_encoding.as_deref(), expected, "{}", coding);
+            let decoded = decode(content_encoding.as_deref(), &body);
+            assert_eq!(decoded, latin1(ACCENTED.as_bytes()), "{}", coding);
+            assert_eq!(*converted.borrow(), words, "{}", coding);
+        }
+    }
+
+    struct PostResource {
+        segments: Vec<&'static str>,
+        redirect: bool,
+        base_uri: Option<&'static str>,
+    }
+
+    impl Webmachine for PostResource {
+        fn allowed_methods<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<Method> {
+            vec![Method::Post]
+        }
+
+        fn base_uri<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+            _req: &Request,
+        ) -> Option<Uri> {
+            self.base_uri.map(|uri| uri.parse().unwrap())
+        }
+
+        fn content_types_accepted<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<(Mime, BodyAcceptor<S>)> {
+            vec![(mime::TEXT_PLAIN, Box::new(|_state, _req, _body| Ok(())))]
+        }
+
+        fn process_post<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+            _req: &Request,
+        ) -> PostResponse<S> {
+            let segments =
+                self.segments.iter().map(|s| s.to_string()).collect();
+            if self.redirect {
+                PostResponse::PostCreateRedirect(segments)
+            } else {
+                PostResponse::PostCreate(segments)
+            }
+        }
+    }
+
+    fn post_location(
+        resource: PostResource,
+        path: &str,
+    ) -> (StatusCode, String) {
+        let mut req =
+            request(Method::Post, path, [("Content-Type", "text/plain")]);
+        req.set_body("new item");
+        let response = run(resource, req);
+        let location = response.headers().get::<Location>().unwrap();
+        (response.status(), location.to_string())
+    }
+
+    #[test]
+    fn post_create_with_unsupported_content_type_has_no_location() {
+        for &redirect in &[false, true] {
+            let resource = PostResource {
+                segments: vec!["42"],
+                redirect,
+                base_uri: None,
+            };
+            let headers = [("Content-Type", "application/json")];
+            let mut req = request(Method::Post, "/collection", headers);
+            req.set_body("{}");
+            let response = run(resource, req);
+            assert_eq!(response.status(), StatusCode::UnsupportedMediaType);
+            assert!(!response.headers().has::<Location>());
+        }
+    }
+
+    #[test]
+    fn post_create_location_is_built_from_request_path() {
+        let cases = vec![
+            (
+                "joins segments",
+                "/collection",
+                vec!["items", "42"],
+                None,
+                "/collection/items/42",
+            ),
+            (
+                "trailing slash",
+                "/collection/",
+                vec!["42"],
+                None,
+                "/collection/42",
+            ),
+            (
+                "encodes segments",
+                "/collection",
+                vec!["a b/c", "100%"],
+                None,
+                "/collection/a%20b%2Fc/100%25",
+            ),
+            ("root path", "/", vec!["42"], None, "/42"),
+            (
+                "absolute base",
+                "/collection",
+                vec!["42"],
+                Some("https://example.com"),
+                "https://example.com/collection/42",
+            ),
+            (
+                "base with prefix",
+                "/collection",
+                vec!["42"],
+                Some("https://example.com/api/"),
+                "https://example.com/api/collection/42",
+            ),
+            (
+                "relative base",
+                "/collection",
+                vec!["42"],
+                Some("/api"),
+                "/api/collection/42",
+            ),
+        ];
+
+        for (name, path, segments, base_uri, expected) in cases {
+            for &redirect in &[false, true] {
+                let resource = PostResource {
+                    segments: segments.clone(),
+                    redirect,
+                    base_uri,
+                };
+                let (status, location) = post_location(resource, path);
+                let expected_status = if redirect {
+                    StatusCode::SeeOther
+                } else {
+                    StatusCode::Created
+                };
+                assert_eq!(status, expected_status, "{}", name);
+                assert_eq!(location, expected, "{}", name);
+            }
+        }
+    }
+
+    struct AcceptingResource {
+        create: bool,
+        body: Option<&'static str>,
+        content_type: Option<Mime>,
+    }
+
+    impl Webmachine for AcceptingResource {
+        fn allowed_methods<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<Method> {
+            vec![Method::Post, Method::Put]
+        }
+
+        fn content_types_accepted<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<(Mime, BodyAcceptor<S>)> {
+            let body = self.body;
+            let content_type = self.content_type.clone();
+            vec![(
+                mime::TEXT_PLAIN,
+                Box::new(move |state: &mut S, _req: &Request, _body: &[u8]| {
+                    if let Some(body) = body {
+                        set_response_body(state, Body::from(body));
+                    }
+                    if let Some(content_type) = content_type {
+                        set_response_header(state, ContentType(content_type));
+                    }
+                    Ok(())
+                }),
+            )]
+        }
+
+        fn content_types_provided<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<(Mime, BodyProducer<S>)> {
+            vec![(
+                mime::APPLICATION_JSON,
+                Box::new(|_state: &mut S, _req: &Request| {
+                    Ok(Body::empty().into())
+                }),
+            )]
+        }
+
+        fn process_post<S: HasAirshipState>(
+            &self,
+            state: &mut S,
+            _req: &Request,
+        ) -> PostResponse<S> {
+            if self.create {
+                PostResponse::PostCreate(vec![String::from("42")])
+            } else {
+                PostResponse::PostProcess(self.content_types_accepted(state))
+            }
+        }
+    }
+
+    fn accepting_response(
+        resource: AcceptingResource,
+        method: Method,
+    ) -> (StatusCode, Option<String>) {
+        let mut req =
+            request(method, "/items", [("Content-Type", "text/plain")]);
+        req.set_body("new item");
+        let response = run(resource, req);
+        let content_type = sent_header::<ContentType>(&response);
+        (response.status(), content_type)
+    }
+
+    struct AcceptedCase {
+        name: &'static str,
+        method: Method,
+        resource: AcceptingResource,
+        status: StatusCode,
+        content_type: Option<&'static str>,
+    }
+
+    #[test]
+    fn accepted_entity_selects_status_and_representation() {
+        let cases = vec![
+            AcceptedCase {
+                name: "post without body",
+                method: Method::Post,
+                resource: AcceptingResource {
+                    create: false,
+                    body: None,
+                    content_type: None,
+                },
+                status: StatusCode::NoContent,
+                content_type: None,
+            },
+            AcceptedCase {
+                name: "post with body",
+                method: Method::Post,
+                resource: AcceptingResource {
+                    create: false,
+                    body: Some("{}"),
+                    content_type: None,
+                },
+                status: StatusCode::Ok,
+                content_type: Some("application/json"),
+            },
+            AcceptedCase {
+                name: "created without body",
+                method: Method::Post,
+                resource: AcceptingResource {
+                    create: true,
+                    body: None,
+                    content_type: None,
+                },
+                status: StatusCode::Created,
+                content_type: None,
+            },
+            AcceptedCase {
+                name: "created with body",
+                method: Method::Post,
+                resource: AcceptingResource {
+                    create: true,
+                    body: Some("{}"),
+                    content_type: None,
+                },
+                status: StatusCode::Created,
+                content_type: Some("application/json"),
+            },
+            AcceptedCase {
+                name: "put without body",
+                method: Method::Put,
+                resource: AcceptingResource {
+                    create: false,
+                    body: None,
+                    content_type: None,
+                },
+                status: StatusCode::NoContent,
+                content_type: None,
+            },
+            AcceptedCase {
+                name: "put with body",
+                method: Method::Put,
+                resource: AcceptingResource {
+                    create: false,
+                    body: Some("{}"),
+                    content_type: None,
+                },
+                status: StatusCode::Ok,
+                content_type: Some("application/json"),
+            },
+            AcceptedCase {
+                name: "own content type",
+                method: Method::Put,
+                resource: AcceptingResource {
+                    create: false,
+                    body: Some("<ok/>"),
+                    content_type: Some(mime::TEXT_XML),
+                },
+                status: StatusCode::Ok,
+                content_type: Some("text/xml"),
+            },
+        ];
+
+        for case in cases {
+            let (status, content_type) =
+                accepting_response(case.resource, case.method);
+            assert_eq!(status, case.status, "{}", case.name);
+            assert_eq!(
+                content_type.as_deref(),
+                case.content_type,
+                "{}",
+                case.name
+            );
+        }
+    }
+
+    struct FailingResource(Error);
+
+    impl Webmachine for FailingResource {
+        fn resource_exists_async<S: HasAirshipState + 'static>(
+            &self,
+            state: S,
+        ) -> StateFuture<S, bool> {
+            Box::new(futures::future::err((state, self.0.clone())))
+        }
+    }
+
+    fn failed_response(
+        error: Error,
+        state: RequestState,
+    ) -> Response<ResponseBody> {
+        let req = request(Method::Get, "/", []);
+        traverse(FailingResource(error), req, state).wait().unwrap()
+    }
+
+    #[test]
+    fn callback_error_halts_with_its_status_and_headers() {
+        let error = Error::new(StatusCode::ServiceUnavailable)
+            .with_header(RetryAfter::Delay(Duration::from_secs(30)))
+            .with_body(mime::APPLICATION_JSON, "{}");
+        let response = failed_response(error, RequestState::new());
+        assert_eq!(response.status(), StatusCode::ServiceUnavailable);
+        assert!(response
+            .headers()
+            .get::<AirshipTrace>()
+            .unwrap()
+            .0
+            .ends_with("g07"));
+        assert_eq!(
+            response.headers().get::<RetryAfter>(),
+            Some(&RetryAfter::Delay(Duration::from_secs(30)))
+        );
+        assert_eq!(
+            response.headers().get::<ContentType>(),
+            Some(&ContentType::json())
+        );
+    }
+
+    #[test]
+    fn callback_error_uses_registered_error_responses() {
+        let mut state = RequestState::new();
+        set_error_response(
+            &mut state,
+            StatusCode::ServiceUnavailable,
+            vec![(mime::TEXT_PLAIN, String::from("try again later"))],
+        );
+        let error = Error::new(StatusCode::ServiceUnavailable);
+        let response = failed_response(error, state);
+        assert_eq!(response.status(), StatusCode::ServiceUnavailable);
+        assert_eq!(
+            response.headers().get::<ContentType>(),
+            Some(&ContentType(mime::TEXT_PLAIN))
+        );
+    }
+
+    const NOT_FOUND_JSON: &str = r#"{"title":"Not Found"}"#;
+    const NOT_FOUND_HTML: &str = "<h1>Not Found</h1>";
+
+    /// A missing resource that registers JSON and HTML bodies for
+    /// `404 Not Found` from inside `resource_exists`, and none for other
+    /// statuses.
+    struct ErrorBodiesResource;
+
+    impl Webmachine for ErrorBodiesResource {
+        fn content_types_provided<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<(Mime, BodyProducer<S>)> {
+            let provided =
+                vec![mime::TEXT_PLAIN, mime::APPLICATION_JSON, mime::TEXT_HTML];
+            provided
+                .into_iter()
+                .map(|content_type| -> (Mime, BodyProducer<S>) {
+                    (content_type, Box::new(|_, _| Ok(Body::empty().into())))
+                })
+                .collect()
+        }
+
+        fn resource_exists<S: HasAirshipState>(&self, state: &mut S) -> bool {
+            set_error_response(
+                state,
+                StatusCode::NotFound,
+                vec![
+                    (mime::APPLICATION_JSON, String::from(NOT_FOUND_JSON)),
+                    (mime::TEXT_HTML, String::from(NOT_FOUND_HTML)),
+                ],
+            );
+            false
+        }
+    }
+
+    struct ErrorBodyCase {
+        name: &'static str,
+        accept: Option<&'static str>,
+        status: StatusCode,
+        content_type: Option<Mime>,
+        body: &'static str,
+    }
+
+    #[test]
+    fn error_body_is_chosen_by_accept() {
+        let cases = vec![
+            ErrorBodyCase {
+                name: "api client",
+                accept: Some("application/json"),
+                status: StatusCode::NotFound,
+                content_type: Some(mime::APPLICATION_JSON),
+                body: NOT_FOUND_JSON,
+            },
+            ErrorBodyCase {
+                name: "browser",
+                accept: Some("text/html,application/xhtml+xml,*/*;q=0.8"),
+                status: StatusCode::NotFound,
+                content_type: Some(mime::TEXT_HTML),
+                body: NOT_FOUND_HTML,
+            },
+            ErrorBodyCase {
+                name: "higher quality wins",
+                accept: Some("text/html;q=0.5, application/json"),
+                status: StatusCode::NotFound,
+                content_type: Some(mime::APPLICATION_JSON),
+                body: NOT_FOUND_JSON,
+            },
+            ErrorBodyCase {
+                name: "no accept falls back to the first entry",
+                accept: None,
+                status: StatusCode::NotFound,
+                content_type: Some(mime::APPLICATION_JSON),
+                body: NOT_FOUND_JSON,
+            },
+            ErrorBodyCase {
+                name: "any type falls back to the first entry",
+                accept: Some("*/*"),
+                status: StatusCode::NotFound,
+                content_type: Some(mime::APPLICATION_JSON),
+                body: NOT_FOUND_JSON,
+            },
+            ErrorBodyCase {
+                name: "no acceptable error body",
+                accept: Some("text/plain"),
+                status: StatusCode::NotFound,
+                content_type: None,
+                body: "",
+            },
+            ErrorBodyCase {
+                name: "no error body registered",
+                accept: Some("image/png"),
+                status: StatusCode::NotAcceptable,
+                content_type: None,
+                body: "",
+            },
+        ];
+
+        for case in cases {
+            let headers = case.accept.map(|value| ("Accept", value));
+            let req = request(Method::Get, "/", headers);
+            let (response, body) = run_with_body(ErrorBodiesResource, req);
+            assert_eq!(response.status(), case.status, "{}", case.name);
+            assert_eq!(
+                response.headers().get::<ContentType>(),
+                case.content_type.map(ContentType).as_ref(),
+                "{}",
+                case.name
+            );
+            assert_eq!(body, case.body.as_bytes(), "{}", case.name);
+        }
+    }
+
+    struct AsyncResource {
+        exists: bool,
+    }
+
+    impl Webmachine for AsyncResource {
+        fn allowed_methods<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<Method> {
+            vec![Method::Get, Method::Put]
+        }
+
+        fn is_conflict_async<S: HasAirshipState + 'static>(
+            &self,
+            state: S,
+        ) -> StateFuture<S, bool> {
+            let error = Error::new(StatusCode::ServiceUnavailable);
+            Box::new(futures::future::err((state, error)))
+        }
+
+        fn moved_permanently_async<S: HasAirshipState + 'static>(
+            &self,
+            state: S,
+        ) -> StateFuture<S, Option<String>> {
+            Box::new(futures::future::ok((state, Some(String::from("/new")))))
+        }
+
+        fn previously_existed_async<S: HasAirshipState + 'static>(
+            &self,
+            state: S,
+        ) -> StateFuture<S, bool> {
+            Box::new(futures::future::ok((state, true)))
+        }
+
+        fn resource_exists<S: HasAirshipState>(&self, _state: &mut S) -> bool {
+            self.exists
+        }
+    }
+
+    #[test]
+    fn async_callbacks_are_chained_through_the_graph() {
+        let cases = vec![
+            (
+                "moved get",
+                false,
+                Method::Get,
+                StatusCode::MovedPermanently,
+            ),
+            (
+                "moved put",
+                false,
+                Method::Put,
+                StatusCode::MovedPermanently,
+            ),
+            (
+                "failed conflict check",
+                true,
+                Method::Put,
+                StatusCode::ServiceUnavailable,
+            ),
+        ];
+
+        for (name, exists, method, status) in cases {
+            let req = request(method, "/old", []);
+            let response = run(AsyncResource { exists }, req);
+            assert_eq!(response.status(), status, "{}", name);
+            if status == StatusCode::MovedPermanently {
+                assert_eq!(
+                    response.headers().get::<Location>(),
+                    Some(&Location::new("/new")),
+                    "{}",
+                    name
+                );
+            }
+        }
+    }
+
+    struct FailingBodies;
+
+    impl Webmachine for FailingBodies {
+        fn allowed_methods<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<Method> {
+            vec![Method::Get, Method::Put, Method::Post]
+        }
+
+        fn content_types_accepted<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<(Mime, BodyAcceptor<S>)> {
+            vec![(
+                mime::TEXT_PLAIN,
+                Box::new(|_state, _req, _body| {
+                    Err(Error::new(StatusCode::UnprocessableEntity))
+                }),
+            )]
+        }
+
+        fn content_types_provided<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<(Mime, BodyProducer<S>)> {
+            vec![(
+                mime::TEXT_PLAIN,
+                Box::new(|_state: &mut S, _req: &Request| {
+                    Err(Error::new(StatusCode::ServiceUnavailable))
+                }),
+            )]
+        }
+
+        fn process_post<S: HasAirshipState>(
+            &self,
+            state: &mut S,
+            _req: &Request,
+        ) -> PostResponse<S> {
+            PostResponse::PostProcess(self.content_types_accepted(state))
+        }
+    }
+
+    #[test]
+    fn failing_acceptor_or_producer_halts_with_its_error() {
+        let cases = vec![
+            ("producer", Method::Get, StatusCode::ServiceUnavailable),
+            ("put acceptor", Method::Put, StatusCode::UnprocessableEntity),
+            (
+                "post acceptor",
+                Method::Post,
+                StatusCode::UnprocessableEntity,
+            ),
+        ];
+
+        for (name, method, status) in cases {
+            let mut req =
+                request(method, "/", [("Content-Type", "text/plain")]);
+            req.set_body("body");
+            let response = run(FailingBodies, req);
+            assert_eq!(response.status(), status, "{}", name);
+        }
+    }
+
+    #[test]
+    fn unreadable_request_body_is_an_internal_error() {
+        let (mut sender, body) = Body::pair();
+        sender.try_send(Err(hyper::Error::Incomplete)).unwrap();
+        drop(sender);
+        let headers = [("Content-Type", "text/plain")];
+        let mut req = request(Method::Put, "/", headers);
+        req.set_body(body);
+        let response = run(FailingBodies, req);
+        assert_eq!(response.status(), StatusCode::InternalServerError);
+    }
+
+    #[test]
+    fn connection_errors_are_kept_as_the_source() {
+        use std::error::Error as _;
+
+        let error = Error::from(hyper::Error::Incomplete);
+        assert_eq!(error.status(), StatusCode::InternalServerError);
+        let source = error.source().expect("hyper error kept as the source");
+        assert_eq!(source.to_string(), hyper::Error::Incomplete.to_string());
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "request failed with 500 Internal Server Error: {}",
+                hyper::Error::Incomplete
+            )
+        );
+    }
+
+    /// Accepts `text/plain` bodies of up to eight bytes and records what its
+    /// acceptor was handed.
+    #[derive(Default)]
+    struct UploadResource {
+        received: Rc<RefCell<Option<Vec<u8>>>>,
+    }
+
+    impl Webmachine for UploadResource {
+        fn allowed_methods<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<Method> {
+            vec![Method::Put]
+        }
+
+        fn content_types_accepted<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<(Mime, BodyAcceptor<S>)> {
+            let received = Rc::clone(&self.received);
+            vec![(
+                mime::TEXT_PLAIN,
+                Box::new(move |_state, _req, body| {
+                    *received.borrow_mut() = Some(body.to_vec());
+                    Ok(())
+                }),
+            )]
+        }
+
+        fn max_body_size<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Option<u64> {
+            Some(8)
+        }
+    }
+
+    /// A body sent in `chunks`, without a `Content-Length`.
+    fn chunked_body(chunks: &[&'static str]) -> Body {
+        let (mut sender, receiver) = futures::sync::mpsc::channel(chunks.len());
+        for chunk in chunks {
+            sender.try_send(Ok(Chunk::from(*chunk))).unwrap();
+        }
+        Body::from(receiver)
+    }
+
+    #[test]
+    fn request_body_is_read_up_to_max_body_size() {
+        let cases = vec![
+            (
+                "within the limit",
+                Some("5"),
+                chunked_body(&["hello"]),
+                StatusCode::NoContent,
+                Some("hello"),
+            ),
+            (
+                "content-length over the limit",
+                Some("9"),
+                chunked_body(&["too large"]),
+                StatusCode::PayloadTooLarge,
+                None,
+            ),
+            (
+                "chunks within the limit",
+                None,
+                chunked_body(&["1234", "5678"]),
+                StatusCode::NoContent,
+                Some("12345678"),
+            ),
+            (
+                "chunks over the limit",
+                None,
+                chunked_body(&["1234", "5678", "9"]),
+                StatusCode::PayloadTooLarge,
+                None,
+            ),
+        ];
+
+        for (name, content_length, body, status, received) in cases {
+            let length = content_length.map(|value| ("Content-Length", value));
+            let headers = length
+                .into_iter()
+                .chain(Some(("Content-Type", "text/plain")));
+            let mut req = request(Method::Put, "/", headers);
+            req.set_body(body);
+            let resource = UploadResource::default();
+            let accepted = Rc::clone(&resource.received);
+            let response = run(resource, req);
+            assert_eq!(response.status(), status, "{}", name);
+            let accepted = accepted.borrow_mut().take();
+            let accepted =
+                accepted.map(|body| String::from_utf8(body).unwrap());
+            assert_eq!(accepted.as_deref(), received, "{}", name);
+        }
+    }
+
+    struct ShrinkingResource(Cell<usize>);
+
+    impl Webmachine for ShrinkingResource {
+        fn content_types_provided<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<(Mime, BodyProducer<S>)> {
+            let calls = self.0.get();
+            self.0.set(calls + 1);
+            let mut provided: Vec<(Mime, BodyProducer<S>)> = vec![(
+                mime::TEXT_HTML,
+                Box::new(|_state: &mut S, _req: &Request| {
+                    Ok(Body::empty().into())
+                }),
+            )];
+            if calls == 0 {
+                provided.push((
+                    mime::APPLICATION_JSON,
+                    Box::new(|_state: &mut S, _req: &Request| {
+                        Ok(Body::empty().into())
+                    }),
+                ));
+            }
+            provided
+        }
+    }
+
+    #[test]
+    fn content_type_missing_from_o18_is_an_internal_error() {
+        let req = request(Method::Get, "/", [("Accept", "application/json")]);
+        let resource = ShrinkingResource(Cell::new(0));
+        let response = run(resource, req);
+        assert_eq!(response.status(), StatusCode::InternalServerError);
+    }
+
+    const ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz";
+
+    #[derive(Default)]
+    struct RangeResource {
+        requested: Rc<RefCell<Vec<ops::Range<u64>>>>,
+    }
+
+    impl Webmachine for RangeResource {
+        fn byte_ranges<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+            _req: &Request,
+        ) -> Option<(u64, RangeProducer<S>)> {
+            let requested = Rc::clone(&self.requested);
+            let producer =
+                move |_state: &mut S,
+                      _req: &Request,
+                      range: ops::Range<u64>| {
+                    requested.borrow_mut().push(range.clone());
+                    Body::from(
+                        &ALPHABET[range.start as usize..range.end as usize],
+                    )
+                };
+            Some((ALPHABET.len() as u64, Box::new(producer)))
+        }
+
+        fn content_types_provided<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<(Mime, BodyProducer<S>)> {
+            vec![(
+                mime::TEXT_PLAIN,
+                Box::new(|_state: &mut S, _req: &Request| {
+                    Ok(Body::from(ALPHABET).into())
+                }),
+            )]
+        }
+
+        fn encodings_provided<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Vec<Encoding> {
+            vec![Encoding::Identity, Encoding::Gzip]
+        }
+
+        fn generate_etag<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+            _req: &Request,
+        ) -> Option<EntityTag> {
+            Some(EntityTag::strong(String::from("v1")))
+        }
+
+        fn last_modified<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+        ) -> Option<HttpDate> {
+            Some("Sun, 06 Nov 1994 08:49:37 GMT".parse().unwrap())
+        }
+    }
+
+    struct RangeCase {
+        name: &'static str,
+        headers: Vec<(&'static str, &'static str)>,
+        status: StatusCode,
+        content_range: Option<&'static str>,
+        accept_ranges: bool,
+        requested: Vec<(u64, u64)>,
+    }
+
+    #[test]
+    fn byte_range_requests_follow_rfc7233() {
+        let cases = vec![
+            RangeCase {
+                name: "no range",
+                headers: vec![],
+                status: StatusCode::Ok,
+                content_range: None,
+                accept_ranges: true,
+                requested: vec![],
+            },
+            RangeCase {
+                name: "single range",
+                headers: vec![("Range", "bytes=0-4")],
+                status: StatusCode::PartialContent,
+                content_range: Some("bytes 0-4/26"),
+                accept_ranges: true,
+                requested: vec![(0, 5)],
+            },
+            RangeCase {
+                name: "suffix range",
+                headers: vec![("Range", "bytes=-3")],
+                status: StatusCode::PartialContent,
+                content_range: Some("bytes 23-25/26"),
+                accept_ranges: true,
+                requested: vec![(23, 26)],
+            },
+            RangeCase {
+                name: "open-ended range past the end",
+                headers: vec![("Range", "bytes=20-99")],
+                status: StatusCode::PartialContent,
+                content_range: Some("bytes 20-25/26"),
+                accept_ranges: true,
+                requested: vec![(20, 26)],
+            },
+            RangeCase {
+                name: "unsatisfiable range",
+                headers: vec![("Range", "bytes=30-40")],
+                status: StatusCode::RangeNotSatisfiable,
+                content_range: Some("bytes */26"),
+                accept_ranges: true,
+                requested: vec![],
+            },
+            RangeCase {
+                name: "unsatisfiable ranges are dropped",
+                headers: vec![("Range", "bytes=30-40,2-3")],
+                status: StatusCode::PartialContent,
+                content_range: Some("bytes 2-3/26"),
+                accept_ranges: true,
+                requested: vec![(2, 4)],
+            },
+            RangeCase {
+                name: "multiple ranges",
+                headers: vec![("Range", "bytes=10-11,0-1")],
+                status: StatusCode::PartialContent,
+                content_range: None,
+                accept_ranges: true,
+                requested: vec![(10, 12), (0, 2)],
+            },
+            RangeCase {
+                name: "overlapping ranges are merged",
+                headers: vec![("Range", "bytes=3-8,0-4")],
+                status: StatusCode::PartialContent,
+                content_range: Some("bytes 0-8/26"),
+                accept_ranges: true,
+                requested: vec![(0, 9)],
+            },
+            RangeCase {
+                name: "other range unit",
+                headers: vec![("Range", "items=0-4")],
+                status: StatusCode::Ok,
+                content_range: None,
+                accept_ranges: true,
+                requested: vec![],
+            },
+            RangeCase {
+                name: "if-range entity tag matches",
+                headers: vec![("Range", "bytes=0-4"), ("If-Range", "\"v1\"")],
+                status: StatusCode::PartialContent,
+                content_range: Some("bytes 0-4/26"),
+                accept_ranges: true,
+                requested: vec![(0, 5)],
+            },
+            RangeCase {
+                name: "if-range entity tag does not match",
+                headers: vec![("Range", "bytes=0-4"), ("If-Range", "\"v0\"")],
+                status: StatusCode::Ok,
+                content_range: None,
+                accept_ranges: true,
+                requested: vec![],
+            },
+            RangeCase {
+                name: "if-range weak entity tag never matches",
+                headers: vec![("Range", "bytes=0-4"), ("If-Range", "W/\"v1\"")],
+                status: StatusCode::Ok,
+                content_range: None,
+                accept_ranges: true,
+                requested: vec![],
+            },
+            RangeCase {
+                name: "if-range date matches",
+                headers: vec![
+                    ("Range", "bytes=0-4"),
+                    ("If-Range", "Sun, 06 Nov 1994 08:49:37 GMT"),
+                ],
+                status: StatusCode::PartialContent,
+                content_range: Some("bytes 0-4/26"),
+                accept_ranges: true,
+                requested: vec![(0, 5)],
+            },
+            RangeCase {
+                name: "if-range date does not match",
+                headers: vec![
+                    ("Range", "bytes=0-4"),
+                    ("If-Range", "Mon, 07 Nov 1994 08:49:37 GMT"),
+                ],
+                status: StatusCode::Ok,
+                content_range: None,
+                accept_ranges: true,
+                requested: vec![],
+            },
+            RangeCase {
+                name: "content coded response",
+                headers: vec![
+                    ("Range", "bytes=0-4"),
+                    ("Accept-Encoding", "gzip"),
+                ],
+                status: StatusCode::Ok,
+                content_range: None,
+                accept_ranges: false,
+                requested: vec![],
+            },
+        ];
+
+        for case in cases {
+            let req = request(Method::Get, "/", case.headers.iter().copied());
+            let resource = RangeResource::default();
+            let requested = Rc::clone(&resource.requested);
+            let response = run(resource, req);
+            let name = case.name;
+            assert_eq!(response.status(), case.status, "{}", name);
+            let content_range = sent_header::<ContentRange>(&response);
+            assert_eq!(
+                content_range.as_deref(),
+                case.content_range,
+                "{}",
+                name
+            );
+            assert_eq!(
+                response.headers().has::<AcceptRanges>(),
+                case.accept_ranges,
+                "{}",
+                name
+            );
+            let requested = requested
+                .borrow()
+                .iter()
+                .map(|range| (range.start, range.end))
+                .collect::<Vec<_>>();
+            assert_eq!(requested, case.requested, "{}", name);
+            if case.status == StatusCode::PartialContent {
+                let content_type =
+                    response.headers().get::<ContentType>().unwrap();
+                let multipart = content_type
+                    .to_string()
+                    .starts_with("multipart/byteranges; boundary=");
+                assert_eq!(multipart, case.requested.len() > 1, "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn excessive_ranges_are_coalesced() {
+        let many = (0..20).map(|i| (i * 2, i * 2)).collect::<Vec<_>>();
+        let cases = vec![
+            ("disjoint", vec![(5, 6), (0, 1)], vec![(5, 6), (0, 1)]),
+            ("overlapping", vec![(5, 9), (0, 6)], vec![(0, 9)]),
+            (
+                "adjoining",
+                vec![(0, 4), (5, 9), (20, 21)],
+                vec![(0, 9), (20, 21)],
+            ),
+            ("contained", vec![(0, 9), (2, 3)], vec![(0, 9)]),
+            ("too many", many, vec![(0, 38)]),
+        ];
+
+        for (name, ranges, expected) in cases {
+            assert_eq!(coalesce_ranges(ranges), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn multipart_byteranges_frames_each_part() {
+        let parts =
+            vec![((10, 11), Body::from("kl")), ((0, 1), Body::from("ab"))];
+        let body = multipart_byteranges("sep", &mime::TEXT_PLAIN, 26, parts)
+            .concat2()
+            .wait()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(body.to_vec()).unwrap(),
+            "\r\n--sep\r\nContent-Type: text/plain\r\nContent-Range: bytes 10-11/26\r\n\r\nkl\
+             \r\n--sep\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/26\r\n\r\nab\
+             \r\n--sep--\r\n"
+        );
+    }
+}
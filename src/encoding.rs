@@ -0,0 +1,153 @@
+//! Content encoders used to compress a response body once an encoding has
+//! been negotiated from the request `Accept-Encoding` header.
+//!
+//! The encoders work on the body one chunk at a time, so a response is
+//! compressed as it is sent rather than buffered in full first.
+
+use std::io;
+use std::io::Write;
+use std::mem;
+
+use brotli::CompressorWriter;
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+use futures::{try_ready, Async, Poll, Stream};
+use hyper::header::Encoding;
+use hyper::Chunk;
+
+/// Compresses a response body incrementally.
+pub trait Encoder {
+    /// Encodes a chunk of the body and returns the output that is ready to
+    /// be sent. The output is flushed so that each chunk reaches the client
+    /// without waiting on the chunks that follow it.
+    fn encode(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>>;
+
+    /// Completes the encoding and returns any remaining output.
+    fn finish(self: Box<Self>) -> io::Result<Vec<u8>>;
+}
+
+/// Returns the built-in encoder for `encoding`, or `None` when the body should
+/// be sent as-is. `identity` and any encoding without a built-in encoder fall
+/// into the latter case.
+pub fn encoder(encoding: &Encoding) -> Option<Box<dyn Encoder>> {
+    match *encoding {
+        Encoding::Gzip => Some(Box::new(GzipEncoder(GzEncoder::new(
+            Vec::new(),
+            Compression::default(),
+        )))),
+        Encoding::Deflate => Some(Box::new(DeflateEncoder(ZlibEncoder::new(
+            Vec::new(),
+            Compression::default(),
+        )))),
+        Encoding::Brotli => Some(Box::new(BrotliEncoder(
+            CompressorWriter::new(Vec::new(), 4096, 5, 22),
+        ))),
+        _ => None,
+    }
+}
+
+/// Whether `encoding` can be applied by one of the built-in encoders.
+/// `identity` is always supported. This is checked on every request, so it
+/// matches on the encodings `encoder` handles rather than building one.
+pub fn is_supported(encoding: &Encoding) -> bool {
+    matches!(
+        *encoding,
+        Encoding::Identity
+            | Encoding::Gzip
+            | Encoding::Deflate
+            | Encoding::Brotli
+    )
+}
+
+struct GzipEncoder(GzEncoder<Vec<u8>>);
+
+impl Encoder for GzipEncoder {
+    fn encode(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        self.0.write_all(chunk)?;
+        self.0.flush()?;
+        Ok(mem::take(self.0.get_mut()))
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<Vec<u8>> {
+        self.0.finish()
+    }
+}
+
+// HTTP's `deflate` content coding is the zlib format rather than a raw
+// deflate stream.
+struct DeflateEncoder(ZlibEncoder<Vec<u8>>);
+
+impl Encoder for DeflateEncoder {
+    fn encode(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        self.0.write_all(chunk)?;
+        self.0.flush()?;
+        Ok(mem::take(self.0.get_mut()))
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<Vec<u8>> {
+        self.0.finish()
+    }
+}
+
+struct BrotliEncoder(CompressorWriter<Vec<u8>>);
+
+impl Encoder for BrotliEncoder {
+    fn encode(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        self.0.write_all(chunk)?;
+        self.0.flush()?;
+        Ok(mem::take(self.0.get_mut()))
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<Vec<u8>> {
+        Ok(self.0.into_inner())
+    }
+}
+
+/// A body stream that passes every chunk of `inner` through an `Encoder`.
+pub struct EncodedBody<B> {
+    inner: B,
+    encoder: Option<Box<dyn Encoder>>,
+}
+
+impl<B> EncodedBody<B> {
+    pub fn new(inner: B, encoder: Box<dyn Encoder>) -> EncodedBody<B> {
+        EncodedBody {
+            inner,
+            encoder: Some(encoder),
+        }
+    }
+}
+
+impl<B> Stream for EncodedBody<B>
+where
+    B: Stream<Item = Chunk, Error = hyper::Error>,
+{
+    type Item = Chunk;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+        loop {
+            if self.encoder.is_none() {
+                return Ok(Async::Ready(None));
+            }
+            match try_ready!(self.inner.poll()) {
+                Some(chunk) => {
+                    let encoder = self.encoder.as_mut().unwrap();
+                    let encoded = encoder.encode(&chunk)?;
+                    // Encoders may hold small chunks back until they have
+                    // enough input, so only send chunks with some output.
+                    if !encoded.is_empty() {
+                        return Ok(Async::Ready(Some(Chunk::from(encoded))));
+                    }
+                }
+                None => {
+                    let encoder = self.encoder.take().unwrap();
+                    let encoded = encoder.finish()?;
+                    if !encoded.is_empty() {
+                        return Ok(Async::Ready(Some(Chunk::from(encoded))));
+                    }
+                }
+            }
+        }
+    }
+}
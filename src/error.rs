@@ -0,0 +1,107 @@
+//! The error type that `Webmachine` callbacks fail with.
+//!
+//! An error halts processing with its status code, in the same way as a
+//! callback that answers a decision with a failing status, so a database
+//! error in `resource_exists_async` can be answered with
+//! `503 Service Unavailable` rather than a misleading `404 Not Found`.
+
+use std::error;
+use std::fmt;
+use std::sync::Arc;
+
+use hyper::header::{Header, Headers};
+use hyper::StatusCode;
+use mime::Mime;
+
+/// An error returned by a `Webmachine` callback.
+///
+/// Without a body of its own, the error is sent with the error responses
+/// registered for its status code, as any other halt is.
+#[derive(Clone, Debug)]
+pub struct Error {
+    pub(crate) status: StatusCode,
+    pub(crate) responses: Vec<(Mime, String)>,
+    pub(crate) headers: Headers,
+    source: Option<Arc<dyn error::Error + Send + Sync>>,
+}
+
+impl Error {
+    pub fn new(status: StatusCode) -> Error {
+        Error {
+            status,
+            responses: vec![],
+            headers: Headers::new(),
+            source: None,
+        }
+    }
+
+    /// Adds a body to send with the error. When several are added, the one
+    /// whose media type best matches the request `Accept` header is sent.
+    pub fn with_body<B: Into<String>>(
+        mut self,
+        content_type: Mime,
+        body: B,
+    ) -> Error {
+        self.responses.push((content_type, body.into()));
+        self
+    }
+
+    /// Adds a header to the error response, such as `Retry-After` on a
+    /// `503 Service Unavailable`.
+    pub fn with_header<H: Header>(mut self, hdr: H) -> Error {
+        self.headers.set(hdr);
+        self
+    }
+
+    /// Records the underlying error that caused this one, so that it can be
+    /// reported alongside the status code.
+    pub fn with_source<E>(mut self, source: E) -> Error
+    where
+        E: error::Error + Send + Sync + 'static,
+    {
+        self.source = Some(Arc::new(source));
+        self
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "request failed with {}", self.status)?;
+        match &self.source {
+            Some(source) => write!(f, ": {}", source),
+            None => Ok(()),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match &self.source {
+            Some(source) => Some(source.as_ref()),
+            None => None,
+        }
+    }
+}
+
+impl From<StatusCode> for Error {
+    fn from(status: StatusCode) -> Error {
+        Error::new(status)
+    }
+}
+
+/// Failures of the underlying connection, such as an error reading the
+/// request body, are answered with `500 Internal Server Error`. The hyper
+/// error is kept as the source.
+impl From<hyper::Error> for Error {
+    fn from(error: hyper::Error) -> Error {
+        Error::new(StatusCode::InternalServerError).with_source(error)
+    }
+}
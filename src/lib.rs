@@ -2,7 +2,11 @@
 extern crate hyper;
 
 pub mod decision;
+pub mod encoding;
+pub mod error;
 pub mod resource;
 pub mod route;
 pub mod server;
+pub mod sse;
 pub mod types;
+pub mod upgrade;
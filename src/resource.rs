@@ -1,20 +1,42 @@
 #![allow(clippy::type_complexity)]
 
+use std::ops;
+
+use futures::{future, Stream};
 use hyper::header::*;
-use hyper::{Body, Method, Request, Uri};
+use hyper::{Body, Chunk, Method, Request, Uri};
 use mime;
 use mime::Mime;
 
 use webmachine_derive::*;
 
-use crate::types::HasAirshipState;
+use crate::error::Error;
+use crate::types::{HasAirshipState, ResponseBody, StateFuture};
+use crate::upgrade::Upgrade;
 
+/// A resource whose callbacks answer the decisions of the request graph.
+///
+/// Only the callbacks with an `_async` variant can fail: the graph calls the
+/// variant, whose `StateFuture` may resolve to an `Error` that halts with the
+/// error's status. The synchronous callbacks themselves cannot fail.
 pub trait Webmachine {
     // Whether to allow HTTP POSTs to a missing resource. Default: false.
+    // Cannot fail; implement 'allow_missing_post_async'
+    // to halt with an 'Error'.
     fn allow_missing_post<S: HasAirshipState>(&self, _state: &mut S) -> bool {
         false
     }
 
+    // Asynchronous variant of 'allow_missing_post'. Defaults to calling
+    // 'allow_missing_post'.
+    fn allow_missing_post_async<S: HasAirshipState + 'static>(
+        &self,
+        mut state: S,
+    ) -> StateFuture<S, bool> {
+        let allowed = self.allow_missing_post(&mut state);
+        Box::new(future::ok((state, allowed)))
+    }
+
     /*
      * The set of HTTP methods that this resource allows. Default: @GET@ and
      * @HEAD@. If a request arrives with an HTTP method not included herein,
@@ -27,23 +49,106 @@ pub trait Webmachine {
         vec![Method::Get, Method::Head, Method::Options]
     }
 
+    /*
+     * The public URI that the resource's @Location@ headers are built on,
+     * for services that sit behind a proxy or are mounted under a path
+     * prefix. The request path is appended to its path, so a base of
+     * @https://example.com/api@ turns a 'PostCreate' at @/items@ into
+     * @https://example.com/api/items/42@. Default: None, which sends a
+     * @Location@ relative to the request's host.
+     */
+    fn base_uri<S: HasAirshipState>(
+        &self,
+        _state: &mut S,
+        _req: &Request,
+    ) -> Option<Uri> {
+        None
+    }
+
+    /*
+     * Opts the resource in to byte range requests. Returns the length of the
+     * negotiated representation together with a 'RangeProducer' that renders
+     * any slice of it, so that a range is served without building the whole
+     * body. When this returns a value, responses advertise
+     * @Accept-Ranges: bytes@ and a @GET@ with a @Range@ header is answered
+     * with @206 Partial Content@, or with @416 Range Not Satisfiable@ if
+     * none of the ranges overlap the representation. Ranges are of the
+     * identity bytes, so they are not served, and this is not called, when
+     * the response is content coded or converted to another charset.
+     * Default: None.
+     */
+    fn byte_ranges<S: HasAirshipState>(
+        &self,
+        _state: &mut S,
+        _req: &Request,
+    ) -> Option<(u64, RangeProducer<S>)> {
+        None
+    }
+
+    /*
+     * The directives sent in the @Cache-Control@ header of successful and
+     * @304 Not Modified@ responses. Default: empty, which sends no header.
+     */
+    fn cache_control<S: HasAirshipState>(
+        &self,
+        _state: &mut S,
+    ) -> Vec<CacheDirective> {
+        vec![]
+    }
+
+    /*
+     * The character sets the resource can render its body in, in order of
+     * preference, each with an optional 'CharsetConverter' that transcodes
+     * the UTF-8 body into it. The charset is chosen with the quality values
+     * in the @Accept-Charset@ header, or is the first entry when the request
+     * has none, and is added to the @Content-Type@ of the response.
+     * Processing halts with @406 Not Acceptable@ if none of them is
+     * acceptable. Default: empty, which skips charset negotiation.
+     */
+    fn charsets_provided<S: HasAirshipState>(
+        &self,
+        _state: &mut S,
+    ) -> Vec<(Charset, Option<CharsetConverter>)> {
+        vec![]
+    }
+
+    /*
+     * The length in bytes of the body a @GET@ request would be sent. When
+     * this returns a length for a @HEAD@ request, it is sent as the
+     * @Content-Length@ and the body producer is not called. It is not used
+     * when the body is converted to another charset or compressed, since
+     * that changes the length. Default: None, which produces the body and
+     * measures it.
+     */
+    fn content_length<S: HasAirshipState>(
+        &self,
+        _state: &mut S,
+        _req: &Request,
+    ) -> Option<u64> {
+        None
+    }
+
     /*
      * An association list of 'MediaType's and 'Webmachine' actions that
      * correspond to the accepted @Content-Type@ values that this resource
      * can accept in a request body. If a @Content-Type@ header is present
      * but not accounted for in 'content_types_accepted', processing will
-     * halt with @415 Unsupported Media Type@. Otherwise, the corresponding
-     * 'Webmachine' action will be executed and processing will continue.
+     * halt with @415 Unsupported Media Type@. Otherwise, the request body is
+     * read and handed to the corresponding 'Webmachine' action and
+     * processing will continue. Each 'Mime' is matched as a media range, so
+     * a wildcard subtype with a @+json@ suffix accepts any JSON-based type,
+     * and parameters are only required to match when the 'Mime' carries
+     * them.
      */
     fn content_types_accepted<S: HasAirshipState>(
         &self,
         _state: &mut S,
-    ) -> Vec<(Mime, fn(&Request))> {
+    ) -> Vec<(Mime, BodyAcceptor<S>)> {
         vec![]
     }
 
     /*
-     * An association list of 'Mime' values and 'ResponseBody' values. The
+     * An association list of 'Mime' values and 'BodyProducer' closures. The
      * response will be chosen by looking up the 'Mime' that most closely
      * matches the @Accept@ header. Should there be no match, processing
      * will halt with @406 Not Acceptable@.
@@ -51,8 +156,26 @@ pub trait Webmachine {
     fn content_types_provided<S: HasAirshipState>(
         &self,
         _state: &mut S,
-    ) -> Vec<(Mime, fn(&Request) -> Body)> {
-        vec![(mime::TEXT_PLAIN, |_x: &Request| Body::empty())]
+    ) -> Vec<(Mime, BodyProducer<S>)> {
+        vec![(
+            mime::TEXT_PLAIN,
+            Box::new(|_state: &mut S, _req: &Request| Ok(Body::empty().into())),
+        )]
+    }
+
+    /*
+     * The content codings the resource can apply to the response body, in
+     * order of preference. The coding is chosen with the quality values in
+     * the @Accept-Encoding@ header, and processing halts with
+     * @406 Not Acceptable@ if none of them is acceptable. @gzip@, @deflate@
+     * and @br@ are applied by airship; other codings are ignored.
+     * Default: identity only.
+     */
+    fn encodings_provided<S: HasAirshipState>(
+        &self,
+        _state: &mut S,
+    ) -> Vec<Encoding> {
+        vec![Encoding::Identity]
     }
 
     /*
@@ -60,14 +183,26 @@ pub trait Webmachine {
      * 'delete_resource'), a @False@ value returns a @202 Accepted@ response.
      * Returning @True@ will continue processing, usually ending up with a
      * @204 No Content@ response. Default: False.
+     * Cannot fail; implement 'delete_completed_async' to halt with an 'Error'.
      */
     fn delete_completed<S: HasAirshipState>(&self, _state: &mut S) -> bool {
         false
     }
 
+    // Asynchronous variant of 'delete_completed'. Defaults to calling
+    // 'delete_completed'.
+    fn delete_completed_async<S: HasAirshipState + 'static>(
+        &self,
+        mut state: S,
+    ) -> StateFuture<S, bool> {
+        let completed = self.delete_completed(&mut state);
+        Box::new(future::ok((state, completed)))
+    }
+
     /*
      * When processing a @DELETE@ request, a @True@ value allows processing
      *  to continue. Returns @500 Forbidden@ if False. Default: false.
+     * Cannot fail; implement 'delete_resource_async' to halt with an 'Error'.
      */
     fn delete_resource<S: HasAirshipState>(
         &self,
@@ -77,18 +212,51 @@ pub trait Webmachine {
         false
     }
 
-    // Returns @413 Request Entity Too Large@ if true. Default: false.
+    /*
+     * Asynchronous variant of 'delete_resource'. This is the callback the
+     * decision graph invokes, so resources that must wait on another
+     * service to enact the delete should override this one instead. The
+     * default implementation defers to 'delete_resource'.
+     */
+    fn delete_resource_async<S: HasAirshipState + 'static>(
+        &self,
+        mut state: S,
+        req: &Request,
+    ) -> StateFuture<S, bool> {
+        let deleted = self.delete_resource(&mut state, req);
+        Box::new(future::ok((state, deleted)))
+    }
+
+    /*
+     * Returns @413 Request Entity Too Large@ if true. Default: true when the
+     * request @Content-Length@ exceeds 'max_body_size', false otherwise.
+     */
     fn entity_too_large<S: HasAirshipState>(
         &self,
-        _state: &mut S,
-        _req: &Request,
+        state: &mut S,
+        req: &Request,
     ) -> bool {
-        false
+        match (
+            self.max_body_size(state),
+            req.headers().get::<ContentLength>(),
+        ) {
+            (Some(max_size), Some(ContentLength(length))) => *length > max_size,
+            _ => false,
+        }
+    }
+
+    /*
+     * If this returns a date, it is sent in the @Expires@ header of
+     * successful and @304 Not Modified@ responses. Default: None.
+     */
+    fn expires<S: HasAirshipState>(&self, _state: &mut S) -> Option<HttpDate> {
+        None
     }
 
     /*
      * Checks if the given request is allowed to access this resource.
      * Returns @403 Forbidden@ if true. Default: false.
+     * Cannot fail; implement 'forbidden_async' to halt with an 'Error'.
      */
     fn forbidden<S: HasAirshipState>(
         &self,
@@ -98,9 +266,23 @@ pub trait Webmachine {
         false
     }
 
+    // Asynchronous variant of 'forbidden'. Defaults to calling 'forbidden'.
+    fn forbidden_async<S: HasAirshipState + 'static>(
+        &self,
+        mut state: S,
+        req: &Request,
+    ) -> StateFuture<S, bool> {
+        let forbidden = self.forbidden(&mut state, req);
+        Box::new(future::ok((state, forbidden)))
+    }
+
     /*
      * If this returns a non-'Nothing' 'ETag', its value will be added to
-     * every HTTP response in the @ETag:@ field.
+     * every HTTP response in the @ETag:@ field. Unlike most decision
+     * callbacks it has no asynchronous variant: it is consulted by several
+     * decisions and again for each response that carries validators, so a
+     * resource should derive the tag from what 'resource_exists_async'
+     * loaded into the request state rather than fetch it here.
      */
     fn generate_etag<S: HasAirshipState>(
         &self,
@@ -110,13 +292,21 @@ pub trait Webmachine {
         None
     }
 
-    // Checks if this resource has actually implemented a handler for a given HTTP method.
-    // Returns @501 Not Implemented@ if false. Default: true.
-    fn implemented<S: HasAirshipState>(&self, _state: &mut S) -> bool {
+    /*
+     * Checks if this resource has actually implemented a handler for the
+     * method of the request, which 'known_methods' has already accepted.
+     * Returns @501 Not Implemented@ if false. Default: true.
+     */
+    fn implemented<S: HasAirshipState>(
+        &self,
+        _state: &mut S,
+        _req: &Request,
+    ) -> bool {
         true
     }
 
     // Returns @401 Unauthorized@ if false. Default: true.
+    // Cannot fail; implement 'is_authorized_async' to halt with an 'Error'.
     fn is_authorized<S: HasAirshipState>(
         &self,
         _state: &mut S,
@@ -125,14 +315,35 @@ pub trait Webmachine {
         true
     }
 
+    // Asynchronous variant of 'is_authorized'. Defaults to calling
+    // 'is_authorized'.
+    fn is_authorized_async<S: HasAirshipState + 'static>(
+        &self,
+        mut state: S,
+        req: &Request,
+    ) -> StateFuture<S, bool> {
+        let authorized = self.is_authorized(&mut state, req);
+        Box::new(future::ok((state, authorized)))
+    }
+
     /*
      * When processing @PUT@ requestsfn a @True@ value returned here will
      * halt processing with a @409 Conflict@.
+     * Cannot fail; implement 'is_conflict_async' to halt with an 'Error'.
      */
     fn is_conflict<S: HasAirshipState>(&self, _state: &mut S) -> bool {
         false
     }
 
+    // Asynchronous variant of 'is_conflict'. Defaults to calling 'is_conflict'.
+    fn is_conflict_async<S: HasAirshipState + 'static>(
+        &self,
+        mut state: S,
+    ) -> StateFuture<S, bool> {
+        let conflict = self.is_conflict(&mut state);
+        Box::new(future::ok((state, conflict)))
+    }
+
     // Returns @415 Unsupported Media Type@ if false. We recommend you use the 'contentTypeMatches' helper functionfn which accepts a list of
     // 'MediaType' valuesfn so as to simplify proper MIME type handling. Default: true.
     fn known_content_type<S: HasAirshipState>(
@@ -143,8 +354,32 @@ pub trait Webmachine {
         true
     }
 
+    /*
+     * The HTTP methods the server understands. A request with any other
+     * method is answered with @501 Not Implemented@ before the resource is
+     * consulted further. Resources that handle extension methods, such as
+     * WebDAV's @PROPFIND@ or @REPORT@, add them here as well as to
+     * 'allowed_methods'. Default: the methods defined by RFC 7231 and
+     * @PATCH@.
+     */
+    fn known_methods<S: HasAirshipState>(&self, _state: &mut S) -> Vec<Method> {
+        vec![
+            Method::Get,
+            Method::Post,
+            Method::Head,
+            Method::Put,
+            Method::Delete,
+            Method::Trace,
+            Method::Connect,
+            Method::Options,
+            Method::Patch,
+        ]
+    }
+
     // In the presence of an @If-Modified-Since@ headerfn returning a @Just@ value from 'lastModifed' allows
-    // the server to halt with @304 Not Modified@ if appropriate.
+    // the server to halt with @304 Not Modified@ if appropriate. Like
+    // 'generate_etag', it is called more than once per request and has no
+    // asynchronous variant, so it should read the date from the request state.
     fn last_modified<S: HasAirshipState>(
         &self,
         _state: &mut S,
@@ -153,16 +388,19 @@ pub trait Webmachine {
     }
 
     /*
-     * If an @Accept-Language@ value is present in the HTTP request, and this
-     * function returns @False@, processing will halt with
-     * @406 Not Acceptable@.
+     * The languages the resource can render its body in, in order of
+     * preference. The language is chosen from the @Accept-Language@ header
+     * with the basic filtering and lookup schemes of RFC 4647, or is the
+     * first entry when the request has none, and is sent as the
+     * @Content-Language@ of the response. Processing halts with
+     * @406 Not Acceptable@ if none of them is acceptable. Default: empty,
+     * which skips language negotiation.
      */
-    fn language_available<H: Header, S: HasAirshipState>(
+    fn languages_provided<S: HasAirshipState>(
         &self,
         _state: &mut S,
-        _accept_lang_header: &H,
-    ) -> bool {
-        true
+    ) -> Vec<LanguageTag> {
+        vec![]
     }
 
     // Returns @400 Bad Request@ if true. Default: false.
@@ -174,11 +412,23 @@ pub trait Webmachine {
         false
     }
 
+    /*
+     * The largest request body, in bytes, that this resource will read
+     * before halting with @413 Request Entity Too Large@. The limit is
+     * checked against @Content-Length@ in 'entity_too_large' and again
+     * while the body is read, so chunked uploads are capped as well.
+     * Default: None (unlimited).
+     */
+    fn max_body_size<S: HasAirshipState>(&self, _state: &mut S) -> Option<u64> {
+        None
+    }
+
     /*
      * When processing a resource for which 'resourceExists' returned
      * @False@, returning a @Just@ value halts with a
      * @301 Moved Permanently@ response. The contained 'String' will be
      * added to the HTTP response under the @Location:@ header.
+     * Cannot fail; implement 'moved_permanently_async' to halt with an 'Error'.
      */
     fn moved_permanently<S: HasAirshipState>(
         &self,
@@ -187,7 +437,18 @@ pub trait Webmachine {
         None
     }
 
+    // Asynchronous variant of 'moved_permanently'. Defaults to calling
+    // 'moved_permanently'.
+    fn moved_permanently_async<S: HasAirshipState + 'static>(
+        &self,
+        mut state: S,
+    ) -> StateFuture<S, Option<String>> {
+        let location = self.moved_permanently(&mut state);
+        Box::new(future::ok((state, location)))
+    }
+
     // Like 'moved_permanently'fn except with a @307 Moved Temporarily@ response.
+    // Cannot fail; implement 'moved_temporarily_async' to halt with an 'Error'.
     fn moved_temporarily<S: HasAirshipState>(
         &self,
         _state: &mut S,
@@ -195,6 +456,16 @@ pub trait Webmachine {
         None
     }
 
+    // Asynchronous variant of 'moved_temporarily'. Defaults to calling
+    // 'moved_temporarily'.
+    fn moved_temporarily_async<S: HasAirshipState + 'static>(
+        &self,
+        mut state: S,
+    ) -> StateFuture<S, Option<String>> {
+        let location = self.moved_temporarily(&mut state);
+        Box::new(future::ok((state, location)))
+    }
+
     /*
      * When handling a @PUT@ requestfn returning @True@ here halts
      * processing with @300 Multiple Choices@. Default: False.
@@ -210,7 +481,7 @@ pub trait Webmachine {
     fn patch_content_types_accepted<S: HasAirshipState>(
         &self,
         _state: &mut S,
-    ) -> Vec<(Mime, fn(&Request))> {
+    ) -> Vec<(Mime, BodyAcceptor<S>)> {
         vec![]
     }
 
@@ -218,40 +489,111 @@ pub trait Webmachine {
      * When processing a request for which 'resource_exists' returned
      * @False@, returning @True@ here allows the 'moved_permanently' and
      * 'moved_temporarily' functions to process the request.
+     * Cannot fail; implement 'previously_existed_async'
+     * to halt with an 'Error'.
      */
     fn previously_existed<S: HasAirshipState>(&self, _state: &mut S) -> bool {
         false
     }
 
+    // Asynchronous variant of 'previously_existed'. Defaults to calling
+    // 'previously_existed'.
+    fn previously_existed_async<S: HasAirshipState + 'static>(
+        &self,
+        mut state: S,
+    ) -> StateFuture<S, bool> {
+        let existed = self.previously_existed(&mut state);
+        Box::new(future::ok((state, existed)))
+    }
+
     /* When handling @POST@ requests the value returned determines whether
      * to treat the request as a @PUT@, a @PUT@ and a redirect or a plain
      * @POST@. See the documentation for 'PostResponse' for more information.
      * The default implemetation returns a 'PostProcess' with an empty
      * handler.
+     * Cannot fail; implement 'process_post_async' to halt with an 'Error'.
      */
     fn process_post<S: HasAirshipState>(
         &self,
         _state: &mut S,
         _req: &Request,
-    ) -> PostResponse {
+    ) -> PostResponse<S> {
         PostResponse::PostProcess(vec![])
     }
 
+    // Asynchronous variant of 'process_post'. Defaults to calling
+    // 'process_post'.
+    fn process_post_async<S: HasAirshipState + 'static>(
+        &self,
+        mut state: S,
+        req: &Request,
+    ) -> StateFuture<S, PostResponse<S>> {
+        let post_response = self.process_post(&mut state, req);
+        Box::new(future::ok((state, post_response)))
+    }
+
     /*
      * Does the resource at this path exist?
      * Returning false from this usually entails a @404 Not Found@ response.
      * (If 'allowMissingPost' returns @True@ or an @If-Match: *@ header is
      * present, it may not).
+     * Cannot fail; implement 'resource_exists_async' to halt with an 'Error'.
      */
     fn resource_exists<S: HasAirshipState>(&self, _state: &mut S) -> bool {
         true
     }
 
+    /*
+     * Asynchronous variant of 'resource_exists'. The request state is moved
+     * into the callback and must be handed back with the result, which lets
+     * a resource stash whatever it loaded for use later in the request.
+     * If the lookup fails, the callback should fail with an 'Error' rather
+     * than answer false, so that the request halts with the error's status
+     * instead of @404 Not Found@. Like the other asynchronous callbacks,
+     * the state is handed back alongside the error. Defaults to calling
+     * 'resource_exists'.
+     */
+    fn resource_exists_async<S: HasAirshipState + 'static>(
+        &self,
+        mut state: S,
+    ) -> StateFuture<S, bool> {
+        let exists = self.resource_exists(&mut state);
+        Box::new(future::ok((state, exists)))
+    }
+
     // Returns @503 Service Unavailable@ if false. Default: true.
+    // Cannot fail; implement 'service_available_async' to halt with an 'Error'.
     fn service_available<S: HasAirshipState>(&self, _state: &mut S) -> bool {
         true
     }
 
+    // Asynchronous variant of 'service_available'. Defaults to calling
+    // 'service_available'.
+    fn service_available_async<S: HasAirshipState + 'static>(
+        &self,
+        mut state: S,
+    ) -> StateFuture<S, bool> {
+        let available = self.service_available(&mut state);
+        Box::new(future::ok((state, available)))
+    }
+
+    /*
+     * Accepts a request to switch protocols, such as a WebSocket handshake
+     * built with 'Upgrade::websocket'. It is called for requests with
+     * @Upgrade@ and @Connection: upgrade@ headers once they have passed
+     * 'is_authorized' and 'forbidden'. Returning an 'Upgrade' answers with
+     * @101 Switching Protocols@ and hands the connection to its handler,
+     * which can capture the route params from the request state. Default:
+     * None, which processes the request as usual.
+     */
+    fn upgrade<S: HasAirshipState>(
+        &self,
+        _state: &mut S,
+        _req: &Request,
+    ) -> Option<Upgrade> {
+        None
+    }
+
     // Returns @414 Request URI Too Long@ if true. Default: false.
     fn uri_too_long<S: HasAirshipState>(
         &self,
@@ -269,6 +611,17 @@ pub trait Webmachine {
     ) -> bool {
         true
     }
+
+    /*
+     * Request headers, beyond those used for content negotiation, that the
+     * response depends on. They are added to the @Vary@ header of
+     * successful and @304 Not Modified@ responses along with @Accept@,
+     * @Accept-Charset@, @Accept-Encoding@ and @Accept-Language@ for each of
+     * those the resource offers more than one choice for. Default: empty.
+     */
+    fn variances<S: HasAirshipState>(&self, _state: &mut S) -> Vec<String> {
+        vec![]
+    }
 }
 
 // #[derive(Clone)]
@@ -284,16 +637,94 @@ pub struct Resource;
 ///
 /// For processing the POST, an association list of `Mime`s and `Webmachine`
 /// actions are required that correspond to the accepted `Content-Type` values
-/// that this resource can accept in a request body.  If a `Content-Type` header
+/// that this resource can accept in a request body. Each action is handed the
+/// collected request body.  If a `Content-Type` header
 /// is present but not accounted for, processing will halt with `415 Unsupported
 /// Media Type`.
-pub enum PostResponse {
+///
+/// The path segments given to `PostCreate` and `PostCreateRedirect` name the
+/// created resource relative to the request path, and are sent in the
+/// `Location` header percent-encoded and joined with `/`. The header is only
+/// added once the request body has been accepted, and not when the acceptor
+/// sets a `Location` of its own.
+pub enum PostResponse<S> {
     /// Treat this request as a `PUT`.
     PostCreate(Vec<String>),
     /// Treat this request as a `PUT`, then redirect.
     PostCreateRedirect(Vec<String>),
     /// Process as a `POST`, but don't redirect.
-    PostProcess(Vec<(Mime, fn(&Request))>),
+    PostProcess(Vec<(Mime, BodyAcceptor<S>)>),
     /// Process and redirect.
-    PostProcessRedirect(Vec<(Mime, fn(&Request) -> String)>),
+    PostProcessRedirect(Vec<(Mime, BodyAcceptor<S, String>)>),
 }
+
+/// Renders the response body for a negotiated content type. The producer is
+/// handed the request state, so anything loaded earlier in the decision walk
+/// (for example in `resource_exists`) can be rendered without being fetched
+/// again. Producers that need the resource itself may capture a clone of it.
+/// A producer that fails halts the request with its error, as a failing
+/// callback does.
+pub type BodyProducer<S> =
+    Box<dyn FnOnce(&mut S, &Request) -> Result<ProvidedBody, Error>>;
+
+/// The body rendered by a `BodyProducer`.
+pub enum ProvidedBody {
+    /// A body that is ready to send, such as one built from a `String`.
+    Full(Body),
+    /// A stream of chunks that is sent with chunked transfer encoding as it
+    /// is produced. The stream is only polled when the connection can take
+    /// more data, so a slow client holds back the producer rather than
+    /// having the rest of the body buffered for it.
+    Stream(ResponseBody),
+}
+
+impl ProvidedBody {
+    /// Wraps a stream of chunks, typically one built from data the producer
+    /// took out of the request state, as a streamed body.
+    pub fn stream<B>(body: B) -> ProvidedBody
+    where
+        B: Stream<Item = Chunk, Error = hyper::Error> + 'static,
+    {
+        ProvidedBody::Stream(Box::new(body))
+    }
+
+    /// Whether the body is a stream, and so of unknown length until it ends.
+    pub fn is_stream(&self) -> bool {
+        match self {
+            ProvidedBody::Full(_) => false,
+            ProvidedBody::Stream(_) => true,
+        }
+    }
+}
+
+impl<T: Into<Body>> std::convert::From<T> for ProvidedBody {
+    fn from(body: T) -> ProvidedBody {
+        ProvidedBody::Full(body.into())
+    }
+}
+
+/// Consumes a request body of an accepted content type. Like `BodyProducer`,
+/// acceptors are handed the request state along with the request and its
+/// collected body.
+///
+/// An acceptor may answer with a body, such as the entity it created, by
+/// setting it with `set_response_body` or `set_response_stream`. The body is
+/// sent in the representation negotiated from the request's `Accept`
+/// headers unless the acceptor also sets a `Content-Type`. A created
+/// resource is answered with `201 Created`; otherwise the response is
+/// `200 OK` with the body, or `204 No Content` without one. An acceptor that
+/// fails, for example on a body it cannot parse, halts the request with its
+/// error.
+pub type BodyAcceptor<S, T = ()> =
+    Box<dyn FnOnce(&mut S, &Request, &[u8]) -> Result<T, Error>>;
+
+/// Renders a slice of a representation for a byte range request. The range
+/// is half-open and lies within the length returned by `byte_ranges`. The
+/// producer is called once for each range in the request.
+pub type RangeProducer<S> =
+    Box<dyn FnMut(&mut S, &Request, ops::Range<u64>) -> Body>;
+
+/// Transcodes a UTF-8 response body into a negotiated charset. The converter
+/// is called with each chunk of the body in turn, so a converter may need to
+/// hold back a character that is split across chunks until the next call.
+pub type CharsetConverter = Box<dyn FnMut(&[u8]) -> Vec<u8>>;
@@ -64,7 +64,7 @@ pub enum RouteLeaf<R> {
     RouteMatch(RoutedResource<R>, Vec<String>),
     RVar,
     RouteMatchOrVar(RoutedResource<R>, Vec<String>),
-    Wildcard(RoutedResource<R>),
+    Wildcard(RoutedResource<R>, Vec<String>),
 }
 
 /// Turns the list of routes in a 'RoutingSpec' into a 'Trie' for efficient
@@ -114,7 +114,7 @@ where
     };
 
     let final_leaf = if is_wild {
-        RouteLeaf::Wildcard(RoutedResource::<R>(route, resource))
+        RouteLeaf::Wildcard(RoutedResource::<R>(route, resource), vars)
     } else {
         RouteLeaf::RouteMatch(RoutedResource::<R>(route, resource), vars)
     };
@@ -192,8 +192,8 @@ where
     R: Webmachine,
 {
     match (l1, l2) {
-        (RouteLeaf::Wildcard(x), _) => RouteLeaf::Wildcard(x),
-        (_, RouteLeaf::Wildcard(y)) => RouteLeaf::Wildcard(y),
+        (RouteLeaf::Wildcard(x, y), _) => RouteLeaf::Wildcard(x, y),
+        (_, RouteLeaf::Wildcard(x, y)) => RouteLeaf::Wildcard(x, y),
         (RouteLeaf::RVar, RouteLeaf::RVar) => RouteLeaf::RVar,
         (RouteLeaf::RVar, RouteLeaf::RouteMatch(x, y)) => {
             RouteLeaf::RouteMatchOrVar(x, y)
@@ -247,22 +247,18 @@ where
     R: Webmachine,
 {
     let match_result = routes.0.prefix_match(&path_info);
-    match_route(&routes.0, match_result, vec![], None)
+    match_route(&routes.0, match_result, vec![])
 }
 
-fn dispatch_list(dispatch: Option<String>, matched: &str) -> Vec<String> {
-    let upd_dispatch = match dispatch {
-        Some(path) => path + matched,
-        None => String::from(""),
-    };
-    upd_dispatch.split('/').map(|s| s.to_string()).collect()
+/// Binds the values captured for `::var::` segments to their names.
+fn params_map(vars: &[String], params: &[String]) -> HashMap<String, String> {
+    vars.iter().cloned().zip(params.iter().cloned()).collect()
 }
 
 fn match_route<'a, R>(
     routes: &'a Trie<String, RouteLeaf<R>>,
     matched: Option<(Box<String>, &'a RouteLeaf<R>, Box<String>)>,
     mut params: Vec<String>,
-    dispatch: Option<String>,
 ) -> Option<(
     &'a RoutedResource<R>,
     (HashMap<String, String>, Vec<String>),
@@ -276,35 +272,18 @@ where
 
         // The matched key is also a prefix of other routes, but the entire path
         // matched so handle like a RouteMatch.
-        Some((
-            ref matched_prefix,
-            RouteLeaf::RouteMatchOrVar(r, vars),
-            ref rest,
-        )) if rest.is_empty() => {
-            let dispatch_list = dispatch_list(dispatch, matched_prefix);
-            let mut params_map = HashMap::new();
-            let iter = vars.iter().zip(params.iter());
-            iter.for_each(|(v, p)| {
-                params_map.insert(v.clone(), p.clone());
-            });
-            Some((r, (params_map, dispatch_list)))
+        Some((_, RouteLeaf::RouteMatchOrVar(r, vars), ref rest))
+            if rest.is_empty() =>
+        {
+            Some((r, (params_map(vars, &params), vec![])))
         }
 
-        // The entire path matched so return the resource, params, and
-        // dispatch path
-        Some((
-            ref matched_prefix,
-            RouteLeaf::RouteMatch(r, vars),
-            ref rest,
-        )) if rest.is_empty() => {
-            let dispatch_list = dispatch_list(dispatch, matched_prefix);
-            let mut params_map = HashMap::new();
-            let iter = vars.iter().zip(params.iter());
-            iter.for_each(|(v, p)| {
-                params_map.insert(v.clone(), p.clone());
-            });
-
-            Some((r, (params_map, dispatch_list)))
+        // The entire path matched so return the resource and params, with
+        // an empty dispatch path as there is no wildcard
+        Some((_, RouteLeaf::RouteMatch(r, vars), ref rest))
+            if rest.is_empty() =>
+        {
+            Some((r, (params_map(vars, &params), vec![])))
         }
 
         Some((ref _matched, RouteLeaf::RouteMatch(_r, _vars), _)) =>
@@ -352,7 +331,6 @@ where
             ]
             .concat();
 
-            let updated_dispatch = dispatch.or_else(|| Some(String::from("")));
             let mut trimmed_rest = rest.trim_start_matches('/').to_string();
             let slash_offset =
                 trimmed_rest.find('/').unwrap_or_else(|| trimmed_rest.len());
@@ -360,15 +338,20 @@ where
                 trimmed_rest.drain(..slash_offset).collect();
             params.push(param_val);
             let match_result = routes.prefix_match(&next_key);
-            match_route(&routes, match_result, params, updated_dispatch)
+            match_route(&routes, match_result, params)
         }
 
         Some((ref _matched, RouteLeaf::RVar, ref _rest)) => None,
 
-        // Encountered a wildcard (star) value in the trie so it's a match
-        Some((ref _matched, RouteLeaf::Wildcard(r), ref rest)) => {
-            let trimmed_rest = rest.trim_start_matches('/').to_string();
-            Some((r, (HashMap::new(), vec![trimmed_rest])))
+        // Encountered a wildcard (star) value in the trie so it's a match,
+        // and the rest of the path is the dispatch path
+        Some((ref _matched, RouteLeaf::Wildcard(r, vars), ref rest)) => {
+            let dispatch = rest
+                .split('/')
+                .filter(|segment| !segment.is_empty())
+                .map(String::from)
+                .collect();
+            Some((r, (params_map(vars, &params), dispatch)))
         }
     }
 }
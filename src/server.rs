@@ -1,15 +1,67 @@
+use std::any::Any;
+use std::cell::RefCell;
 use std::net::SocketAddr;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::rc::Rc;
 use std::sync::Arc;
 
-use futures::Future;
+use futures::{future, try_ready, Async, Future, Poll, Stream};
+use hyper::header::Accept;
 use hyper::server::{Http, Request, Response, Service};
-use hyper::StatusCode;
+use hyper::{Chunk, Method, StatusCode};
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Core;
 
 use crate::decision;
 use crate::resource::Webmachine;
 use crate::route;
 use crate::route::{RoutingSpec, RoutingTrie};
-use crate::types::HasAirshipState;
+use crate::types::{
+    copy_trace_to, default_error_responses, set_body_wrapper,
+    set_request_accept, set_routing_info, set_trace, set_upgrade_slot,
+    ErrorResponses, HasAirshipState, ResponseBody,
+};
+use crate::upgrade::{UpgradeSlot, Upgraded};
+
+/// Server-wide settings shared by every request.
+#[derive(Default)]
+pub struct Config {
+    /// Error responses used for any status code that the request state (or
+    /// the resource, from inside its callbacks) does not override.
+    pub error_responses: ErrorResponses,
+    /// Called when a resource callback panics. The request is answered with
+    /// `500 Internal Server Error` whether or not a hook is set. Also called
+    /// when a response body or an upgrade handler panics, which ends the
+    /// body or closes the upgraded connection.
+    pub on_panic: Option<Box<PanicHook>>,
+}
+
+/// Reports a panic caught while processing a request, for example by logging
+/// it.
+pub type PanicHook = dyn Fn(&Panic) + Send + Sync;
+
+/// A panic caught while processing a request.
+pub struct Panic<'a> {
+    pub method: &'a Method,
+    pub path: &'a str,
+    /// The decision nodes visited before the panic; the last is the one
+    /// whose callback panicked.
+    pub trace: &'a [String],
+    /// The value the callback panicked with.
+    pub payload: &'a (dyn Any + Send),
+}
+
+impl<'a> Panic<'a> {
+    /// The panic message, if the payload is a string as it is for panics
+    /// raised with `panic!`.
+    pub fn message(&self) -> Option<&str> {
+        match self.payload.downcast_ref::<&str>() {
+            Some(message) => Some(message),
+            None => self.payload.downcast_ref::<String>().map(String::as_str),
+        }
+    }
+}
 
 struct Airship<R, S, F>
 where
@@ -18,7 +70,9 @@ where
     F: Fn() -> S,
 {
     routes: Arc<RoutingTrie<R>>,
+    config: Arc<Config>,
     new_request_state: F,
+    upgrade: UpgradeSlot,
 }
 
 impl<R, S, F> Airship<R, S, F>
@@ -29,55 +83,609 @@ where
 {
     fn new(
         routes: Arc<RoutingTrie<R>>,
+        config: Arc<Config>,
         new_request_state: F,
+        upgrade: UpgradeSlot,
     ) -> Airship<R, S, F> {
         Airship {
             routes: Arc::clone(&routes),
+            config: Arc::clone(&config),
             new_request_state,
+            upgrade,
         }
     }
 }
 
 impl<R, S, F> Service for Airship<R, S, F>
 where
-    S: HasAirshipState,
-    R: Webmachine + Clone,
-    F: Fn() -> S,
+    S: HasAirshipState + 'static,
+    R: Webmachine + Clone + 'static,
+    F: Fn() -> S + Clone + 'static,
 {
     // boilerplate hooking up hyper's server types
     type Request = Request;
-    type Response = Response;
+    type Response = Response<ResponseBody>;
     type Error = hyper::Error;
-    type Future = Box<dyn Future<Item = Response, Error = hyper::Error>>;
+    type Future =
+        Box<dyn Future<Item = Response<ResponseBody>, Error = hyper::Error>>;
 
     fn call(&self, req: Request) -> Self::Future {
+        let mut request_state = self.new_request_state();
         match route::route(&(*self.routes), req.path().to_string()) {
-            Some(routed_resource) => {
-                let r = &(routed_resource.0).1;
-                let mut request_state = (self.new_request_state)();
-                decision::traverse::<R, S>(&r, &req, &mut request_state)
+            Some((routed_resource, (params, dispatch))) => {
+                let r = routed_resource.1.clone();
+                set_routing_info(&mut request_state, params, dispatch);
+                self.traverse(r, req, request_state)
             }
-            None => Box::new(futures::future::ok(
-                Response::new().with_status(StatusCode::NotFound),
-            )),
+            None => {
+                let accept = req.headers().get::<Accept>().cloned();
+                set_request_accept(&mut request_state, accept);
+                decision::halt(StatusCode::NotFound, request_state)
+            }
+        }
+    }
+}
+
+impl<R, S, F> Airship<R, S, F>
+where
+    S: HasAirshipState + 'static,
+    R: Webmachine + Clone + 'static,
+    F: Fn() -> S + Clone + 'static,
+{
+    fn new_request_state(&self) -> S {
+        new_request_state(&self.new_request_state, &self.config, &self.upgrade)
+    }
+
+    // Callbacks run both while the traversal is set up and as its future is
+    // polled, so panics are caught in each. A panic leaves the request state
+    // in an unknown condition, so the 500 is sent from a fresh state that
+    // only carries over the copy of the decision trace.
+    fn traverse(
+        &self,
+        r: R,
+        req: Request,
+        mut request_state: S,
+    ) -> <Self as Service>::Future {
+        let reporter = Rc::new(PanicReporter {
+            config: Arc::clone(&self.config),
+            method: req.method().clone(),
+            path: req.path().to_string(),
+            trace: Rc::new(RefCell::new(vec![])),
+        });
+        copy_trace_to(&mut request_state, Rc::clone(&reporter.trace));
+        let body_reporter = Rc::clone(&reporter);
+        set_body_wrapper(
+            &mut request_state,
+            Box::new(move |body| {
+                Box::new(CatchUnwindBody {
+                    body: Some(body),
+                    reporter: body_reporter,
+                })
+            }),
+        );
+        let accept = req.headers().get::<Accept>().cloned();
+        let new_state = self.new_request_state.clone();
+        let config = Arc::clone(&self.config);
+        let upgrade = Rc::clone(&self.upgrade);
+        let recover = {
+            let reporter = Rc::clone(&reporter);
+            let upgrade = Rc::clone(&upgrade);
+            move |payload: Box<dyn Any + Send>| {
+                reporter.report(&*payload);
+                let mut state =
+                    new_request_state(&new_state, &config, &upgrade);
+                set_request_accept(&mut state, accept);
+                set_trace(&mut state, reporter.trace.borrow().clone());
+                decision::halt(StatusCode::InternalServerError, state)
+            }
+        };
+
+        let traversal = panic::catch_unwind(AssertUnwindSafe(|| {
+            decision::traverse::<R, S>(r, req, request_state)
+        }));
+        match traversal {
+            Ok(traversal) => {
+                Box::new(AssertUnwindSafe(traversal).catch_unwind().then(
+                    move |result| match result {
+                        Ok(response) => {
+                            catch_upgrade_panics(&upgrade, reporter);
+                            Box::new(future::result(response))
+                        }
+                        Err(payload) => recover(payload),
+                    },
+                ))
+            }
+            Err(payload) => recover(payload),
+        }
+    }
+}
+
+/// Reports the panics raised while answering a request to the `on_panic`
+/// hook.
+struct PanicReporter {
+    config: Arc<Config>,
+    method: Method,
+    path: String,
+    trace: Rc<RefCell<Vec<String>>>,
+}
+
+impl PanicReporter {
+    fn report(&self, payload: &(dyn Any + Send)) {
+        if let Some(on_panic) = &self.config.on_panic {
+            on_panic(&Panic {
+                method: &self.method,
+                path: &self.path,
+                trace: &self.trace.borrow(),
+                payload,
+            });
         }
     }
 }
 
+/// A response body that ends when it panics. The response head has been
+/// sent by the time the body is polled, so the panic can only be reported.
+struct CatchUnwindBody {
+    body: Option<ResponseBody>,
+    reporter: Rc<PanicReporter>,
+}
+
+impl Stream for CatchUnwindBody {
+    type Item = Chunk;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+        let polled = match &mut self.body {
+            Some(body) => panic::catch_unwind(AssertUnwindSafe(|| body.poll())),
+            None => return Ok(Async::Ready(None)),
+        };
+        polled.unwrap_or_else(|payload| {
+            self.body = None;
+            self.reporter.report(&*payload);
+            Ok(Async::Ready(None))
+        })
+    }
+}
+
+/// Wraps the handler of an accepted upgrade, if there is one, so that a
+/// panic while it runs is reported and closes the connection.
+fn catch_upgrade_panics(upgrade: &UpgradeSlot, reporter: Rc<PanicReporter>) {
+    let mut slot = upgrade.borrow_mut();
+    let handler = match slot.take() {
+        Some(handler) => handler,
+        None => return,
+    };
+    *slot = Some(Box::new(move |upgraded| {
+        match panic::catch_unwind(AssertUnwindSafe(|| handler(upgraded))) {
+            Ok(running) => {
+                Box::new(AssertUnwindSafe(running).catch_unwind().then(
+                    move |result| match result {
+                        Ok(result) => result,
+                        Err(payload) => {
+                            reporter.report(&*payload);
+                            Ok(())
+                        }
+                    },
+                ))
+            }
+            Err(payload) => {
+                reporter.report(&*payload);
+                Box::new(future::ok(()))
+            }
+        }
+    }));
+}
+
+fn new_request_state<S, F>(
+    new_state: &F,
+    config: &Config,
+    upgrade: &UpgradeSlot,
+) -> S
+where
+    S: HasAirshipState,
+    F: Fn() -> S,
+{
+    let mut request_state = new_state();
+    default_error_responses(&mut request_state, &config.error_responses);
+    set_upgrade_slot(&mut request_state, Rc::clone(upgrade));
+    request_state
+}
+
 pub fn run<R: 'static, S>(
     addr: SocketAddr,
     routes: &[(&str, R)],
     state_fun: &'static dyn Fn() -> S,
 ) where
-    S: HasAirshipState,
+    S: HasAirshipState + 'static,
     R: Webmachine + Clone,
+{
+    run_with_config(addr, routes, state_fun, Config::default())
+}
+
+/// Like `run`, but with server-wide settings such as default error responses.
+pub fn run_with_config<R, S>(
+    addr: SocketAddr,
+    routes: &[(&str, R)],
+    state_fun: &'static dyn Fn() -> S,
+    config: Config,
+) where
+    S: HasAirshipState + 'static,
+    R: Webmachine + Clone + 'static,
 {
     let routing_spec = RoutingSpec(routes.to_owned());
     let routing_trie = Arc::new(RoutingTrie::from(routing_spec));
-    let server = Http::new()
-        .bind(&addr, move || {
-            Ok(Airship::new(Arc::clone(&routing_trie), state_fun))
-        })
-        .unwrap();
-    server.run().unwrap();
+    let config = Arc::new(config);
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let listener = TcpListener::bind(&addr, &handle).unwrap();
+    let http = Http::<hyper::Chunk>::new();
+
+    let server = listener.incoming().for_each(|(sock, _addr)| {
+        let upgrade: UpgradeSlot = Rc::new(RefCell::new(None));
+        let service = Airship::new(
+            Arc::clone(&routing_trie),
+            Arc::clone(&config),
+            state_fun,
+            Rc::clone(&upgrade),
+        );
+        // The connection is driven without shutting it down so that, once
+        // a `101` response has been sent, the socket can be handed to the
+        // resource's upgrade handler.
+        let mut conn = Some(http.serve_connection(sock, service));
+        let served = future::poll_fn(move || {
+            try_ready!(conn.as_mut().unwrap().poll_without_shutdown());
+            Ok(Async::Ready(conn.take().unwrap()))
+        });
+        let connection =
+            served.map_err(|_: hyper::Error| ()).and_then(move |conn| {
+                let handler = upgrade.borrow_mut().take();
+                let upgraded: Box<dyn Future<Item = (), Error = ()>> =
+                    match handler {
+                        Some(handler) => {
+                            let parts = conn.into_parts();
+                            let io = Upgraded {
+                                io: parts.io,
+                                read_buf: parts.read_buf.to_vec(),
+                            };
+                            Box::new(handler(io).map_err(|_| ()))
+                        }
+                        None => Box::new(future::ok(())),
+                    };
+                upgraded
+            });
+        handle.spawn(connection);
+        Ok(())
+    });
+    core.run(server).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    use tokio_core::net::TcpStream;
+
+    use crate::decision::AirshipTrace;
+    use crate::types::RequestState;
+    use crate::upgrade::Upgrade;
+
+    #[derive(Clone)]
+    enum Routes {
+        Healthy,
+        Panicking,
+    }
+
+    impl Webmachine for Routes {
+        fn resource_exists<S: HasAirshipState>(&self, _state: &mut S) -> bool {
+            match self {
+                Routes::Healthy => true,
+                Routes::Panicking => panic!("resource_exists failed"),
+            }
+        }
+    }
+
+    type Reported = Arc<Mutex<Vec<(String, Option<String>, Option<String>)>>>;
+
+    fn reporting_config(reported: &Reported) -> Config {
+        let reported = Arc::clone(reported);
+        Config {
+            on_panic: Some(Box::new(move |panic: &Panic| {
+                reported.lock().unwrap().push((
+                    panic.path.to_string(),
+                    panic.trace.last().cloned(),
+                    panic.message().map(String::from),
+                ));
+            })),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn panicking_resource_is_answered_with_500() {
+        let reported = Arc::new(Mutex::new(vec![]));
+        let config = reporting_config(&reported);
+        let routes = vec![
+            ("healthy", Routes::Healthy),
+            ("panicking", Routes::Panicking),
+        ];
+        let airship = Airship::new(
+            Arc::new(RoutingTrie::from(RoutingSpec(routes))),
+            Arc::new(config),
+            RequestState::new,
+            Rc::new(RefCell::new(None)),
+        );
+
+        let req = Request::new(Method::Get, "/panicking".parse().unwrap());
+        let response = airship.call(req).wait().unwrap();
+        assert_eq!(response.status(), StatusCode::InternalServerError);
+        let trace = response.headers().get::<AirshipTrace>().unwrap();
+        assert!(trace.0.ends_with(",g07"));
+        assert_eq!(
+            *reported.lock().unwrap(),
+            vec![(
+                String::from("/panicking"),
+                Some(String::from("g07")),
+                Some(String::from("resource_exists failed")),
+            )]
+        );
+
+        let req = Request::new(Method::Get, "/healthy".parse().unwrap());
+        let response = airship.call(req).wait().unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+    }
+
+    type Routed = Arc<Mutex<Vec<(HashMap<String, String>, Vec<String>)>>>;
+
+    /// Records the routing information it finds in the request state.
+    #[derive(Clone)]
+    struct RouteRecorder(Routed);
+
+    impl Webmachine for RouteRecorder {
+        fn resource_exists<S: HasAirshipState>(&self, state: &mut S) -> bool {
+            let routed = (
+                state.routing_params().clone(),
+                state.dispatch_path().clone(),
+            );
+            self.0.lock().unwrap().push(routed);
+            true
+        }
+    }
+
+    #[test]
+    fn routing_params_and_dispatch_path_come_from_the_matched_route() {
+        let routed: Routed = Arc::new(Mutex::new(vec![]));
+        let recorder = RouteRecorder(Arc::clone(&routed));
+        let routes = vec![
+            ("users </> ::id::", recorder.clone()),
+            ("files </> ::owner:: </> *", recorder.clone()),
+            ("static </> *", recorder),
+        ];
+        let airship = Airship::new(
+            Arc::new(RoutingTrie::from(RoutingSpec(routes))),
+            Arc::new(Config::default()),
+            RequestState::new,
+            Rc::new(RefCell::new(None)),
+        );
+
+        let cases = vec![
+            ("/users/42", vec![("id", "42")], vec![]),
+            (
+                "/files/ann/a/b.txt",
+                vec![("owner", "ann")],
+                vec!["a", "b.txt"],
+            ),
+            ("/static/css/site.css", vec![], vec!["css", "site.css"]),
+            ("/static", vec![], vec![]),
+        ];
+        for (path, params, dispatch) in cases {
+            let req = Request::new(Method::Get, path.parse().unwrap());
+            let response = airship.call(req).wait().unwrap();
+            assert_eq!(response.status(), StatusCode::Ok, "{}", path);
+            let params = params
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect();
+            let dispatch = dispatch.into_iter().map(String::from).collect();
+            assert_eq!(
+                routed.lock().unwrap().pop(),
+                Some((params, dispatch)),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[derive(Clone)]
+    struct Socket {
+        authorized: bool,
+        forbidden: bool,
+        rooms: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Webmachine for Socket {
+        fn is_authorized<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+            _req: &Request,
+        ) -> bool {
+            self.authorized
+        }
+
+        fn forbidden<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+            _req: &Request,
+        ) -> bool {
+            self.forbidden
+        }
+
+        fn upgrade<S: HasAirshipState>(
+            &self,
+            state: &mut S,
+            req: &Request,
+        ) -> Option<Upgrade> {
+            let room = state.routing_params().get("room").cloned();
+            let rooms = Arc::clone(&self.rooms);
+            Upgrade::websocket(req, move |_upgraded| {
+                rooms.lock().unwrap().extend(room);
+                Box::new(future::ok(()))
+            })
+        }
+    }
+
+    fn websocket_request(path: &str) -> Request {
+        let mut req = Request::new(Method::Get, path.parse().unwrap());
+        let headers = req.headers_mut();
+        headers.set_raw("Connection", "Upgrade");
+        headers.set_raw("Upgrade", "websocket");
+        headers.set_raw("Sec-WebSocket-Version", "13");
+        headers.set_raw("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==");
+        req
+    }
+
+    #[test]
+    fn websocket_upgrade_is_accepted_after_authorization() {
+        let rooms = Arc::new(Mutex::new(vec![]));
+        let socket = |authorized, forbidden| Socket {
+            authorized,
+            forbidden,
+            rooms: Arc::clone(&rooms),
+        };
+        let routes = vec![
+            ("open </> ::room::", socket(true, false)),
+            ("unauthorized </> ::room::", socket(false, false)),
+            ("forbidden </> ::room::", socket(true, true)),
+        ];
+        let upgrade: UpgradeSlot = Rc::new(RefCell::new(None));
+        let airship = Airship::new(
+            Arc::new(RoutingTrie::from(RoutingSpec(routes))),
+            Arc::new(Config::default()),
+            RequestState::new,
+            Rc::clone(&upgrade),
+        );
+
+        let cases = vec![
+            ("/unauthorized/lobby", StatusCode::Unauthorized),
+            ("/forbidden/lobby", StatusCode::Forbidden),
+        ];
+        for (path, status) in cases {
+            let response =
+                airship.call(websocket_request(path)).wait().unwrap();
+            assert_eq!(response.status(), status, "{}", path);
+            assert!(upgrade.borrow().is_none(), "{}", path);
+        }
+
+        let response = airship
+            .call(websocket_request("/open/lobby"))
+            .wait()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SwitchingProtocols);
+        assert_eq!(
+            response.headers().get_raw("Sec-WebSocket-Accept"),
+            Some(&"s3pPLMBiTxaQ9kYGzzhZRbK+xOo=".into())
+        );
+        let handler = upgrade.borrow_mut().take().unwrap();
+
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let listener = TcpListener::bind(&addr, &handle).unwrap();
+        let connect =
+            TcpStream::connect(&listener.local_addr().unwrap(), &handle);
+        let io = core.run(connect).unwrap();
+        let upgraded = Upgraded {
+            io,
+            read_buf: vec![],
+        };
+        core.run(handler(upgraded)).unwrap();
+        assert_eq!(*rooms.lock().unwrap(), vec![String::from("lobby")]);
+    }
+
+    #[test]
+    fn panicking_response_body_is_ended_and_reported() {
+        let reported = Arc::new(Mutex::new(vec![]));
+        let reporter = Rc::new(PanicReporter {
+            config: Arc::new(reporting_config(&reported)),
+            method: Method::Get,
+            path: String::from("/events"),
+            trace: Rc::new(RefCell::new(vec![String::from("o18")])),
+        });
+        let chunks = vec![Chunk::from("a"), Chunk::from("b")];
+        let body: ResponseBody =
+            Box::new(futures::stream::iter_ok(chunks).map(|chunk| {
+                if chunk.as_ref() == b"b" {
+                    panic!("body failed");
+                }
+                chunk
+            }));
+        let body = CatchUnwindBody {
+            body: Some(body),
+            reporter,
+        };
+
+        let sent = body.concat2().wait().unwrap();
+        assert_eq!(sent.as_ref(), b"a");
+        assert_eq!(
+            *reported.lock().unwrap(),
+            vec![(
+                String::from("/events"),
+                Some(String::from("o18")),
+                Some(String::from("body failed")),
+            )]
+        );
+    }
+
+    #[derive(Clone)]
+    struct PanickingSocket;
+
+    impl Webmachine for PanickingSocket {
+        fn upgrade<S: HasAirshipState>(
+            &self,
+            _state: &mut S,
+            req: &Request,
+        ) -> Option<Upgrade> {
+            Upgrade::websocket(req, |_upgraded| panic!("handler failed"))
+        }
+    }
+
+    #[test]
+    fn panicking_upgrade_handler_is_reported() {
+        let reported = Arc::new(Mutex::new(vec![]));
+        let routes = vec![("socket", PanickingSocket)];
+        let upgrade: UpgradeSlot = Rc::new(RefCell::new(None));
+        let airship = Airship::new(
+            Arc::new(RoutingTrie::from(RoutingSpec(routes))),
+            Arc::new(reporting_config(&reported)),
+            RequestState::new,
+            Rc::clone(&upgrade),
+        );
+
+        let response =
+            airship.call(websocket_request("/socket")).wait().unwrap();
+        assert_eq!(response.status(), StatusCode::SwitchingProtocols);
+        let handler = upgrade.borrow_mut().take().unwrap();
+
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let listener = TcpListener::bind(&addr, &handle).unwrap();
+        let connect =
+            TcpStream::connect(&listener.local_addr().unwrap(), &handle);
+        let io = core.run(connect).unwrap();
+        let upgraded = Upgraded {
+            io,
+            read_buf: vec![],
+        };
+        assert!(core.run(handler(upgraded)).is_ok());
+        assert_eq!(
+            *reported.lock().unwrap(),
+            vec![(
+                String::from("/socket"),
+                Some(String::from("upgrade")),
+                Some(String::from("handler failed")),
+            )]
+        );
+    }
 }
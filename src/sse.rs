@@ -0,0 +1,230 @@
+//! A resource that sends a stream of server-sent events as
+//! `text/event-stream`, following the event stream format of the HTML
+//! Living Standard.
+//!
+//! An `EventSource` is mounted in a `RoutingSpec` like any other resource,
+//! usually as a variant of the application's resource enum, and goes through
+//! the full decision graph before its events are streamed.
+
+use std::fmt;
+use std::sync::Arc;
+
+use futures::Stream;
+use hyper::header::CacheDirective;
+use hyper::{Chunk, Method, Request};
+use mime;
+use mime::Mime;
+
+use crate::resource::{BodyProducer, ProvidedBody, Webmachine};
+use crate::types::HasAirshipState;
+
+/// A single server-sent event.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Event {
+    pub id: Option<String>,
+    pub event: Option<String>,
+    pub data: String,
+    pub retry: Option<u64>,
+}
+
+impl Event {
+    /// An unnamed event carrying `data`, which is dispatched to the client's
+    /// `message` handler.
+    pub fn new<D: Into<String>>(data: D) -> Event {
+        Event {
+            data: data.into(),
+            ..Event::default()
+        }
+    }
+
+    /// Sets the event ID, which the client sends back in the
+    /// `Last-Event-ID` header when it reconnects.
+    pub fn with_id<I: Into<String>>(mut self, id: I) -> Event {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// Sets the event type, which selects the client-side handler.
+    pub fn with_event<E: Into<String>>(mut self, event: E) -> Event {
+        self.event = Some(event.into());
+        self
+    }
+
+    /// Sets the time in milliseconds the client waits before reconnecting.
+    pub fn with_retry(mut self, retry: u64) -> Event {
+        self.retry = Some(retry);
+        self
+    }
+}
+
+/// Writes the event in the event stream format, including the blank line
+/// that ends it. Each line of multi-line data is sent as a field of its own,
+/// splitting on `\r\n`, `\r` and `\n` as the client does, and an empty line
+/// is kept as an empty field so that the client rebuilds the same data. Line
+/// breaks in the ID and event type would end the field early, so they are
+/// dropped.
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(id) = &self.id {
+            writeln!(f, "id: {}", single_line(id))?;
+        }
+        if let Some(event) = &self.event {
+            writeln!(f, "event: {}", single_line(event))?;
+        }
+        if let Some(retry) = self.retry {
+            writeln!(f, "retry: {}", retry)?;
+        }
+        for line in data_lines(&self.data) {
+            if line.is_empty() {
+                writeln!(f, "data:")?;
+            } else {
+                writeln!(f, "data: {}", line)?;
+            }
+        }
+        writeln!(f)
+    }
+}
+
+fn data_lines(data: &str) -> Vec<&str> {
+    let mut lines = vec![];
+    let mut rest = data;
+    while let Some(end) = rest.find(['\r', '\n']) {
+        lines.push(&rest[..end]);
+        rest = if rest[end..].starts_with("\r\n") {
+            &rest[end + 2..]
+        } else {
+            &rest[end + 1..]
+        };
+    }
+    lines.push(rest);
+    lines
+}
+
+fn single_line(value: &str) -> String {
+    value.chars().filter(|c| *c != '\r' && *c != '\n').collect()
+}
+
+/// The events sent on a connection to an `EventSource`.
+pub type EventStream = Box<dyn Stream<Item = Event, Error = hyper::Error>>;
+
+/// Opens the event stream for a request. It is handed the request and the
+/// `Last-Event-ID` the client sent, if it is resuming an earlier stream, so
+/// that it can start after the last event the client saw.
+pub type EventStreamProducer =
+    dyn Fn(&Request, Option<String>) -> EventStream + Send + Sync;
+
+/// A resource that answers `GET` requests with a `text/event-stream` body.
+#[derive(Clone)]
+pub struct EventSource {
+    events: Arc<EventStreamProducer>,
+}
+
+impl EventSource {
+    pub fn new<F>(events: F) -> EventSource
+    where
+        F: Fn(&Request, Option<String>) -> EventStream + Send + Sync + 'static,
+    {
+        EventSource {
+            events: Arc::new(events),
+        }
+    }
+}
+
+impl Webmachine for EventSource {
+    fn allowed_methods<S: HasAirshipState>(
+        &self,
+        _state: &mut S,
+    ) -> Vec<Method> {
+        vec![Method::Get, Method::Head, Method::Options]
+    }
+
+    // Events are only meaningful when they arrive, so intermediaries must
+    // not replay a stored stream.
+    fn cache_control<S: HasAirshipState>(
+        &self,
+        _state: &mut S,
+    ) -> Vec<CacheDirective> {
+        vec![CacheDirective::NoCache, CacheDirective::NoStore]
+    }
+
+    fn content_types_provided<S: HasAirshipState>(
+        &self,
+        _state: &mut S,
+    ) -> Vec<(Mime, BodyProducer<S>)> {
+        let events = Arc::clone(&self.events);
+        vec![(
+            mime::TEXT_EVENT_STREAM,
+            Box::new(move |_state: &mut S, req: &Request| {
+                let stream = events(req, last_event_id(req))
+                    .map(|event| Chunk::from(event.to_string()));
+                Ok(ProvidedBody::stream(stream))
+            }),
+        )]
+    }
+}
+
+/// The event ID a reconnecting client sends to resume a stream.
+pub fn last_event_id(req: &Request) -> Option<String> {
+    req.headers()
+        .get_raw("Last-Event-ID")
+        .and_then(|raw| raw.one())
+        .and_then(|value| String::from_utf8(value.to_vec()).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_are_written_in_the_event_stream_format() {
+        let cases = vec![
+            ("single line", Event::new("hello"), "data: hello\n\n"),
+            ("empty data", Event::new(""), "data:\n\n"),
+            (
+                "multi-line data",
+                Event::new("a\nb\r\nc\rd"),
+                "data: a\ndata: b\ndata: c\ndata: d\n\n",
+            ),
+            ("trailing newline", Event::new("a\n"), "data: a\ndata:\n\n"),
+            (
+                "blank lines",
+                Event::new("a\r\n\r\nb"),
+                "data: a\ndata:\ndata: b\n\n",
+            ),
+            (
+                "all fields",
+                Event::new("x")
+                    .with_id("7")
+                    .with_event("update")
+                    .with_retry(3000),
+                "id: 7\nevent: update\nretry: 3000\ndata: x\n\n",
+            ),
+            (
+                "line breaks in id and event",
+                Event::new("x").with_id("1\r\n2").with_event("up\ndate"),
+                "id: 12\nevent: update\ndata: x\n\n",
+            ),
+        ];
+
+        for (name, event, expected) in cases {
+            assert_eq!(event.to_string(), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn last_event_id_is_read_from_the_request() {
+        let cases = vec![
+            ("absent", None, None),
+            ("present", Some(&b"42"[..]), Some("42")),
+            ("not utf-8", Some(&b"\xff"[..]), None),
+        ];
+
+        for (name, header, expected) in cases {
+            let mut req = Request::new(Method::Get, "/".parse().unwrap());
+            if let Some(value) = header {
+                req.headers_mut().set_raw("Last-Event-ID", value.to_vec());
+            }
+            assert_eq!(last_event_id(&req).as_deref(), expected, "{}", name);
+        }
+    }
+}
@@ -1,31 +1,83 @@
 #![allow(clippy::type_complexity)]
 
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
 use std::time::SystemTime;
 
+use futures::{Future, Stream};
 use hyper::header::*;
-use hyper::{Body, Request, Response};
+use hyper::{Body, Chunk, Response, StatusCode};
 
 use mime::Mime;
 
-// type ErrorResponses = Map HTTP.Status [(MediaType, ResponseBody)]
-pub type ErrorResponses = String;
+use crate::error::Error;
+use crate::upgrade::{Upgrade, UpgradeSlot};
+
+/// Response bodies to send when processing halts with a given status code.
+/// Each status maps to a list of media types and bodies, and the body sent is
+/// the one whose media type best matches the request `Accept` header. A
+/// status with no entry is sent with an empty body.
+pub type ErrorResponses = HashMap<StatusCode, Vec<(Mime, String)>>;
+
+/// The future returned by the asynchronous `Webmachine` callbacks. The request
+/// state is moved into the callback and handed back alongside the result so
+/// that the callback may update it once its work completes. A callback that
+/// fails hands the state back alongside an `Error`, and processing halts with
+/// the error's status code.
+pub type StateFuture<S, T> = Box<dyn Future<Item = (S, T), Error = (S, Error)>>;
+
+/// The body of a response sent by airship. Bodies set with
+/// `set_response_body` are sent as they are; a response stream set with
+/// `set_response_stream` is sent with chunked transfer encoding.
+pub type ResponseBody = Box<dyn Stream<Item = Chunk, Error = hyper::Error>>;
+
+/// Wraps the body of the response a request is answered with, just before it
+/// is sent.
+pub(crate) type BodyWrapper = Box<dyn FnOnce(ResponseBody) -> ResponseBody>;
 
 pub struct AirshipState {
     pub error_responses: ErrorResponses,
     pub decision_trace: Vec<String>,
-    pub matched_content_type: Option<(Mime, fn(&Request) -> Body)>,
+    pub matched_content_type: Option<Mime>,
+    pub matched_content_index: Option<usize>,
+    pub matched_charset: Option<Charset>,
+    pub matched_encoding: Option<Encoding>,
+    pub matched_language: Option<LanguageTag>,
     pub response: Option<Response>,
+    pub response_stream: Option<ResponseBody>,
     pub request_time: SystemTime,
+    pub routing_params: HashMap<String, String>,
+    pub dispatch_path: Vec<String>,
+    pub request_accept: Option<Accept>,
+    pub variances: Vec<String>,
+    pub upgrade: Option<UpgradeSlot>,
+    // Receives each decision node as it is traced, so that the server still
+    // has a copy of the trace when a panic takes the request state with it.
+    pub(crate) trace_copy: Option<Rc<RefCell<Vec<String>>>>,
+    pub(crate) body_wrapper: Option<BodyWrapper>,
 }
 
 impl AirshipState {
     pub fn new() -> AirshipState {
         AirshipState {
-            error_responses: String::from(""),
+            error_responses: HashMap::new(),
             decision_trace: vec![],
             matched_content_type: None,
+            matched_content_index: None,
+            matched_charset: None,
+            matched_encoding: None,
+            matched_language: None,
             response: Some(Response::new()),
+            response_stream: None,
             request_time: SystemTime::now(),
+            routing_params: HashMap::new(),
+            dispatch_path: vec![],
+            request_accept: None,
+            variances: vec![],
+            upgrade: None,
+            trace_copy: None,
+            body_wrapper: None,
         }
     }
 }
@@ -39,6 +91,18 @@ impl Default for AirshipState {
 pub trait HasAirshipState {
     fn get_airship_state_mut(&mut self) -> &mut AirshipState;
     fn get_airship_state(&self) -> &AirshipState;
+
+    /// The values bound to the `::var::` segments of the matched route, keyed
+    /// by variable name.
+    fn routing_params(&self) -> &HashMap<String, String> {
+        &self.get_airship_state().routing_params
+    }
+
+    /// The segments of the request path matched by the trailing `*` of a
+    /// wildcard route. Empty for routes without a wildcard.
+    fn dispatch_path(&self) -> &Vec<String> {
+        &self.get_airship_state().dispatch_path
+    }
 }
 
 pub fn get_trace<S>(state: &S) -> &Vec<String>
@@ -49,17 +113,37 @@ where
     &airship_state.decision_trace
 }
 
+/// Replaces the decision trace, such as with the trace of a request whose
+/// state was lost to a panic.
+pub fn set_trace<S>(state: &mut S, trace: Vec<String>)
+where
+    S: HasAirshipState,
+{
+    let airship_state = state.get_airship_state_mut();
+    airship_state.decision_trace = trace;
+}
+
+/// Copies each decision node the request visits from now on into `copy`.
+pub(crate) fn copy_trace_to<S>(state: &mut S, copy: Rc<RefCell<Vec<String>>>)
+where
+    S: HasAirshipState,
+{
+    let airship_state = state.get_airship_state_mut();
+    airship_state.trace_copy = Some(copy);
+}
+
 pub fn trace<S>(state: &mut S, t: &str)
 where
     S: HasAirshipState,
 {
     let airship_state = state.get_airship_state_mut();
     airship_state.decision_trace.push(String::from(t));
+    if let Some(copy) = &airship_state.trace_copy {
+        copy.borrow_mut().push(String::from(t));
+    }
 }
 
-pub fn get_matched_content_type<S>(
-    state: &mut S,
-) -> &mut Option<(Mime, fn(&Request) -> Body)>
+pub fn get_matched_content_type<S>(state: &mut S) -> &mut Option<Mime>
 where
     S: HasAirshipState,
 {
@@ -67,16 +151,82 @@ where
     &mut airship_state.matched_content_type
 }
 
-pub fn matched_content_type<S>(
-    state: &mut S,
-    matched: Option<(Mime, fn(&Request) -> Body)>,
-) where
+pub fn matched_content_type<S>(state: &mut S, matched: Option<Mime>)
+where
     S: HasAirshipState,
 {
     let airship_state = state.get_airship_state_mut();
     airship_state.matched_content_type = matched;
 }
 
+/// The position of the negotiated content type in the resource's
+/// `content_types_provided`, used to find its `BodyProducer` in `o18`.
+pub fn get_matched_content_index<S>(state: &S) -> Option<usize>
+where
+    S: HasAirshipState,
+{
+    let airship_state = state.get_airship_state();
+    airship_state.matched_content_index
+}
+
+pub fn matched_content_index<S>(state: &mut S, matched: Option<usize>)
+where
+    S: HasAirshipState,
+{
+    let airship_state = state.get_airship_state_mut();
+    airship_state.matched_content_index = matched;
+}
+
+pub fn get_matched_charset<S>(state: &S) -> Option<&Charset>
+where
+    S: HasAirshipState,
+{
+    let airship_state = state.get_airship_state();
+    airship_state.matched_charset.as_ref()
+}
+
+pub fn matched_charset<S>(state: &mut S, matched: Option<Charset>)
+where
+    S: HasAirshipState,
+{
+    let airship_state = state.get_airship_state_mut();
+    airship_state.matched_charset = matched;
+}
+
+pub fn get_matched_encoding<S>(state: &S) -> Option<&Encoding>
+where
+    S: HasAirshipState,
+{
+    let airship_state = state.get_airship_state();
+    airship_state.matched_encoding.as_ref()
+}
+
+pub fn matched_encoding<S>(state: &mut S, matched: Option<Encoding>)
+where
+    S: HasAirshipState,
+{
+    let airship_state = state.get_airship_state_mut();
+    airship_state.matched_encoding = matched;
+}
+
+/// The language negotiated from the `Accept-Language` header, if the
+/// resource provides any languages.
+pub fn get_matched_language<S>(state: &S) -> Option<&LanguageTag>
+where
+    S: HasAirshipState,
+{
+    let airship_state = state.get_airship_state();
+    airship_state.matched_language.as_ref()
+}
+
+pub fn matched_language<S>(state: &mut S, matched: Option<LanguageTag>)
+where
+    S: HasAirshipState,
+{
+    let airship_state = state.get_airship_state_mut();
+    airship_state.matched_language = matched;
+}
+
 pub fn set_response_header<H, S>(state: &mut S, hdr: H)
 where
     H: Header,
@@ -88,6 +238,95 @@ where
     }
 }
 
+pub fn has_response_header<H, S>(state: &S) -> bool
+where
+    H: Header,
+    S: HasAirshipState,
+{
+    let airship_state = state.get_airship_state();
+    match &airship_state.response {
+        Some(resp) => resp.headers().has::<H>(),
+        None => false,
+    }
+}
+
+/// Adds `headers` to the response, replacing any that are already set.
+pub fn set_response_headers<S>(state: &mut S, headers: &Headers)
+where
+    S: HasAirshipState,
+{
+    let airship_state = state.get_airship_state_mut();
+    if let Some(resp) = &mut airship_state.response {
+        resp.headers_mut().extend(headers.iter())
+    }
+}
+
+pub fn set_routing_info<S>(
+    state: &mut S,
+    params: HashMap<String, String>,
+    dispatch: Vec<String>,
+) where
+    S: HasAirshipState,
+{
+    let airship_state = state.get_airship_state_mut();
+    airship_state.routing_params = params;
+    airship_state.dispatch_path = dispatch;
+}
+
+/// Sets the bodies sent when processing halts with `status_code`, replacing
+/// any that were configured on the server or earlier in the request.
+pub fn set_error_response<S>(
+    state: &mut S,
+    status_code: StatusCode,
+    responses: Vec<(Mime, String)>,
+) where
+    S: HasAirshipState,
+{
+    let airship_state = state.get_airship_state_mut();
+    airship_state.error_responses.insert(status_code, responses);
+}
+
+/// Adds the server-wide error responses to the request state without
+/// replacing entries the request state already has.
+pub fn default_error_responses<S>(state: &mut S, defaults: &ErrorResponses)
+where
+    S: HasAirshipState,
+{
+    let airship_state = state.get_airship_state_mut();
+    for (status_code, responses) in defaults {
+        airship_state
+            .error_responses
+            .entry(*status_code)
+            .or_insert_with(|| responses.clone());
+    }
+}
+
+pub fn set_request_accept<S>(state: &mut S, accept: Option<Accept>)
+where
+    S: HasAirshipState,
+{
+    let airship_state = state.get_airship_state_mut();
+    airship_state.request_accept = accept;
+}
+
+/// The request headers the negotiated representation varies by, which are
+/// sent as the `Vary` header of a successful or `304 Not Modified` response.
+pub fn get_variances<S>(state: &S) -> &Vec<String>
+where
+    S: HasAirshipState,
+{
+    let airship_state = state.get_airship_state();
+    &airship_state.variances
+}
+
+pub fn set_variances<S>(state: &mut S, variances: Vec<String>)
+where
+    S: HasAirshipState,
+{
+    let airship_state = state.get_airship_state_mut();
+    airship_state.variances = variances;
+}
+
 pub fn request_time<S>(state: &S) -> HttpDate
 where
     S: HasAirshipState,
@@ -101,14 +340,12 @@ where
     S: HasAirshipState,
 {
     let airship_state = state.get_airship_state();
-    if let Some(resp) = &airship_state.response {
-        if let Some(body) = resp.body_ref() {
-            body.is_empty()
-        } else {
-            false
-        }
-    } else {
-        false
+    if airship_state.response_stream.is_some() {
+        return false;
+    }
+    match airship_state.response.as_ref().and_then(Response::body_ref) {
+        Some(body) => body.is_empty(),
+        None => true,
     }
 }
 
@@ -117,7 +354,7 @@ where
     S: HasAirshipState,
 {
     let airship_state = state.get_airship_state_mut();
-    airship_state.response.take().unwrap_or_else(Response::new)
+    airship_state.response.take().unwrap_or_default()
 }
 
 pub fn set_response_body<S>(state: &mut S, body: Body)
@@ -130,6 +367,96 @@ where
     }
 }
 
+/// Sets a stream as the response body, taking the place of any body set with
+/// `set_response_body`.
+pub fn set_response_stream<S>(state: &mut S, stream: ResponseBody)
+where
+    S: HasAirshipState,
+{
+    let airship_state = state.get_airship_state_mut();
+    airship_state.response_stream = Some(stream);
+}
+
+pub fn take_response_stream<S>(state: &mut S) -> Option<ResponseBody>
+where
+    S: HasAirshipState,
+{
+    let airship_state = state.get_airship_state_mut();
+    airship_state.response_stream.take()
+}
+
+/// Has `wrapper` wrap the body of the response the request is answered with.
+pub(crate) fn set_body_wrapper<S>(state: &mut S, wrapper: BodyWrapper)
+where
+    S: HasAirshipState,
+{
+    let airship_state = state.get_airship_state_mut();
+    airship_state.body_wrapper = Some(wrapper);
+}
+
+/// Passes the body of the response through the request's body wrapper, if
+/// it has one.
+pub(crate) fn wrap_response_body<S>(
+    state: &mut S,
+    body: ResponseBody,
+) -> ResponseBody
+where
+    S: HasAirshipState,
+{
+    let airship_state = state.get_airship_state_mut();
+    match airship_state.body_wrapper.take() {
+        Some(wrapper) => wrapper(body),
+        None => body,
+    }
+}
+
+/// Takes the response body out of the request state, leaving the response
+/// with an empty body. A stream set with `set_response_stream` is taken in
+/// preference to a body set with `set_response_body`.
+pub fn take_response_body<S>(state: &mut S) -> ResponseBody
+where
+    S: HasAirshipState,
+{
+    if let Some(stream) = take_response_stream(state) {
+        return stream;
+    }
+    let airship_state = state.get_airship_state_mut();
+    let response = airship_state.response.take().unwrap_or_default();
+    let emptied = Response::new()
+        .with_status(response.status())
+        .with_headers(response.headers().clone());
+    airship_state.response = Some(emptied);
+    Box::new(response.body())
+}
+
+/// Gives the request access to the connection's upgrade slot, which the
+/// server checks once the response has been sent.
+pub fn set_upgrade_slot<S>(state: &mut S, slot: UpgradeSlot)
+where
+    S: HasAirshipState,
+{
+    let airship_state = state.get_airship_state_mut();
+    airship_state.upgrade = Some(slot);
+}
+
+/// Stores the handler of an accepted upgrade for the server to run, and adds
+/// the upgrade's headers to the response. Returns false, leaving the state
+/// unchanged, when the request was not served over a connection that can be
+/// upgraded.
+pub fn accept_upgrade<S>(state: &mut S, upgrade: Upgrade) -> bool
+where
+    S: HasAirshipState,
+{
+    let airship_state = state.get_airship_state_mut();
+    let slot = match &airship_state.upgrade {
+        Some(slot) => slot,
+        None => return false,
+    };
+    *slot.borrow_mut() = Some(upgrade.handler);
+    set_response_headers(state, &upgrade.headers);
+    true
+}
+
 pub struct RequestState(AirshipState);
 
 impl RequestState {
@@ -0,0 +1,153 @@
+//! Protocol upgrades, such as a WebSocket handshake, for resources mounted in
+//! a `RoutingSpec`.
+//!
+//! A resource accepts an upgrade from its `upgrade` callback, which is called
+//! once the request has passed `is_authorized` and `forbidden`. The server
+//! answers with `101 Switching Protocols` and, once the response is written,
+//! hands the connection to the `UpgradeHandler` the resource returned.
+
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+
+use futures::Future;
+use hyper::header;
+use hyper::header::{ConnectionOption, Headers, Protocol, ProtocolName, Raw};
+use hyper::{Method, Request};
+use sha1::Sha1;
+use tokio_core::net::TcpStream;
+use unicase::Ascii;
+
+/// A connection taken over from the HTTP server after a protocol switch.
+pub struct Upgraded {
+    /// The client connection.
+    pub io: TcpStream,
+    /// Bytes the client sent after the upgrade request, which were read by
+    /// the HTTP server before the switch and belong to the new protocol.
+    pub read_buf: Vec<u8>,
+}
+
+/// Runs the new protocol on an upgraded connection. The connection is closed
+/// when the handler's future resolves, unless the handler keeps it.
+pub type UpgradeHandler =
+    Box<dyn FnOnce(Upgraded) -> Box<dyn Future<Item = (), Error = io::Error>>>;
+
+/// Holds the handler for an accepted upgrade until the server has sent the
+/// `101` response on the connection.
+pub type UpgradeSlot = Rc<RefCell<Option<UpgradeHandler>>>;
+
+/// An upgrade accepted by a resource.
+pub struct Upgrade {
+    pub(crate) headers: Headers,
+    pub(crate) handler: UpgradeHandler,
+}
+
+impl Upgrade {
+    /// Switches to `protocol`, running `handler` on the connection. The
+    /// `Upgrade` and `Connection` headers of the `101` response are set.
+    pub fn new<F>(protocol: Protocol, handler: F) -> Upgrade
+    where
+        F: FnOnce(Upgraded) -> Box<dyn Future<Item = (), Error = io::Error>>
+            + 'static,
+    {
+        let mut headers = Headers::new();
+        headers.set(header::Upgrade(vec![protocol]));
+        headers.set(header::Connection(vec![
+            ConnectionOption::ConnectionHeader(Ascii::new(
+                "upgrade".to_owned(),
+            )),
+        ]));
+        Upgrade {
+            headers,
+            handler: Box::new(handler),
+        }
+    }
+
+    /// Adds a header to the `101` response.
+    pub fn with_header<H: header::Header>(mut self, hdr: H) -> Upgrade {
+        self.headers.set(hdr);
+        self
+    }
+
+    /// Accepts a WebSocket opening handshake as described in RFC 6455,
+    /// section 4.2. Returns `None` when `req` is not a valid handshake, in
+    /// which case the resource should let the request be processed as usual.
+    /// The handler speaks the WebSocket protocol itself; subprotocols and
+    /// extensions can be selected with `with_header`.
+    pub fn websocket<F>(req: &Request, handler: F) -> Option<Upgrade>
+    where
+        F: FnOnce(Upgraded) -> Box<dyn Future<Item = (), Error = io::Error>>
+            + 'static,
+    {
+        if *req.method() != Method::Get
+            || !is_upgrade_request(req)
+            || !requests_protocol(req, &ProtocolName::WebSocket)
+            || raw_header(req, "Sec-WebSocket-Version") != Some(b"13")
+        {
+            return None;
+        }
+        let key = raw_header(req, "Sec-WebSocket-Key")?;
+        match base64::decode(key) {
+            Ok(ref nonce) if nonce.len() == 16 => {}
+            _ => return None,
+        }
+        let protocol = Protocol::new(ProtocolName::WebSocket, None);
+        let mut upgrade = Upgrade::new(protocol, handler);
+        upgrade
+            .headers
+            .set_raw("Sec-WebSocket-Accept", websocket_accept(key));
+        Some(upgrade)
+    }
+}
+
+/// Whether the request asks to switch protocols, that is whether it has an
+/// `Upgrade` header and lists `upgrade` in its `Connection` header.
+pub fn is_upgrade_request(req: &Request) -> bool {
+    let connection_upgrade = match req.headers().get::<header::Connection>() {
+        Some(header::Connection(options)) => {
+            options.iter().any(|option| match option {
+                ConnectionOption::ConnectionHeader(name) => {
+                    name.eq_ignore_ascii_case("upgrade")
+                }
+                _ => false,
+            })
+        }
+        None => false,
+    };
+    connection_upgrade && req.headers().has::<header::Upgrade>()
+}
+
+fn requests_protocol(req: &Request, name: &ProtocolName) -> bool {
+    match req.headers().get::<header::Upgrade>() {
+        Some(header::Upgrade(protocols)) => {
+            protocols.iter().any(|protocol| protocol.name == *name)
+        }
+        None => false,
+    }
+}
+
+fn raw_header<'a>(req: &'a Request, name: &str) -> Option<&'a [u8]> {
+    req.headers().get_raw(name).and_then(Raw::one)
+}
+
+const WEBSOCKET_GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The `Sec-WebSocket-Accept` value that answers a `Sec-WebSocket-Key`.
+fn websocket_accept(key: &[u8]) -> String {
+    let mut input = key.to_vec();
+    input.extend_from_slice(WEBSOCKET_GUID);
+    base64::encode(&Sha1::from(&input).digest().bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn websocket_accept_matches_rfc6455_example() {
+        assert_eq!(
+            websocket_accept(b"dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+}
@@ -57,85 +57,154 @@ fn impl_webmachine_enum_variants(
 ) -> proc_macro2::TokenStream
 {
     let allow_missing_post_variants = impl_allow_missing_post(name, variants);
+    let allow_missing_post_async_variants = impl_allow_missing_post_async(name, variants);
     let allowed_methods_variants = impl_allowed_methods(name, variants);
+    let base_uri_variants = impl_base_uri(name, variants);
+    let byte_ranges_variants = impl_byte_ranges(name, variants);
+    let cache_control_variants = impl_cache_control(name, variants);
+    let charsets_provided_variants = impl_charsets_provided(name, variants);
+    let content_length_variants = impl_content_length(name, variants);
     let content_types_accepted_variants = impl_content_types_accepted(name, variants);
     let content_types_provided_variants = impl_content_types_provided(name, variants);
     let delete_completed_variants = impl_delete_completed(name, variants);
+    let delete_completed_async_variants = impl_delete_completed_async(name, variants);
     let delete_resource_variants = impl_delete_resource(name, variants);
+    let delete_resource_async_variants = impl_delete_resource_async(name, variants);
+    let encodings_provided_variants = impl_encodings_provided(name, variants);
     let entity_too_large_variants = impl_entity_too_large(name, variants);
+    let expires_variants = impl_expires(name, variants);
     let forbidden_variants = impl_forbidden(name, variants);
+    let forbidden_async_variants = impl_forbidden_async(name, variants);
     let generate_etag_variants = impl_generate_etag(name, variants);
     let implemented_variants = impl_implemented(name, variants);
     let is_authorized_variants = impl_is_authorized(name, variants);
+    let is_authorized_async_variants = impl_is_authorized_async(name, variants);
     let is_conflict_variants = impl_is_conflict(name, variants);
+    let is_conflict_async_variants = impl_is_conflict_async(name, variants);
     let known_content_type_variants = impl_known_content_type(name, variants);
+    let known_methods_variants = impl_known_methods(name, variants);
     let last_modified_variants = impl_last_modified(name, variants);
-    let language_available_variants = impl_language_available(name, variants);
+    let languages_provided_variants = impl_languages_provided(name, variants);
     let malformed_request_variants = impl_malformed_request(name, variants);
+    let max_body_size_variants = impl_max_body_size(name, variants);
     let moved_permanently_variants = impl_moved_permanently(name, variants);
+    let moved_permanently_async_variants = impl_moved_permanently_async(name, variants);
     let moved_temporarily_variants = impl_moved_temporarily(name, variants);
+    let moved_temporarily_async_variants = impl_moved_temporarily_async(name, variants);
     let multiple_choices_variants = impl_multiple_choices(name, variants);
     let patch_content_types_accepted_variants = impl_patch_content_types_accepted(name, variants);
     let previously_existed_variants = impl_previously_existed(name, variants);
+    let previously_existed_async_variants = impl_previously_existed_async(name, variants);
     let process_post_variants = impl_process_post(name, variants);
+    let process_post_async_variants = impl_process_post_async(name, variants);
     let resource_exists_variants = impl_resource_exists(name, variants);
+    let resource_exists_async_variants = impl_resource_exists_async(name, variants);
     let service_available_variants = impl_service_available(name, variants);
+    let service_available_async_variants = impl_service_available_async(name, variants);
+    let upgrade_variants = impl_upgrade(name, variants);
     let uri_too_long_variants = impl_uri_too_long(name, variants);
     let valid_content_headers_variants = impl_valid_content_headers(name, variants);
+    let variances_variants = impl_variances(name, variants);
 
     quote! {
         impl Webmachine for #name {
             #allow_missing_post_variants
 
+            #allow_missing_post_async_variants
+
             #allowed_methods_variants
 
+            #base_uri_variants
+
+            #byte_ranges_variants
+
+            #cache_control_variants
+
+            #charsets_provided_variants
+
+            #content_length_variants
+
             #content_types_accepted_variants
 
             #content_types_provided_variants
 
             #delete_completed_variants
 
+            #delete_completed_async_variants
+
             #delete_resource_variants
 
+            #delete_resource_async_variants
+
+            #encodings_provided_variants
+
             #entity_too_large_variants
 
+            #expires_variants
+
             #forbidden_variants
 
+            #forbidden_async_variants
+
             #generate_etag_variants
 
             #implemented_variants
 
             #is_authorized_variants
 
+            #is_authorized_async_variants
+
             #is_conflict_variants
 
+            #is_conflict_async_variants
+
             #known_content_type_variants
 
+            #known_methods_variants
+
             #last_modified_variants
 
-            #language_available_variants
+            #languages_provided_variants
 
             #malformed_request_variants
 
+            #max_body_size_variants
+
             #moved_permanently_variants
 
+            #moved_permanently_async_variants
+
             #moved_temporarily_variants
 
+            #moved_temporarily_async_variants
+
             #multiple_choices_variants
 
             #patch_content_types_accepted_variants
 
             #previously_existed_variants
 
+            #previously_existed_async_variants
+
             #process_post_variants
 
+            #process_post_async_variants
+
             #resource_exists_variants
 
+            #resource_exists_async_variants
+
             #service_available_variants
 
+            #service_available_async_variants
+
+            #upgrade_variants
+
             #uri_too_long_variants
 
             #valid_content_headers_variants
+
+            #variances_variants
         }
     }
 }
@@ -192,6 +261,28 @@ fn impl_allow_missing_post(
     }
 }
 
+fn impl_allow_missing_post_async(
+    name: &syn::Ident,
+    variants: &Punctuated<Variant, Comma>
+) -> proc_macro2::TokenStream
+{
+    let callback_method = quote! {
+        allow_missing_post_async
+    };
+    let trailing_args = quote! {};
+    let variants = variants
+        .iter()
+        .map(|variant| impl_webmachine_enum_variant(name, &callback_method, &trailing_args, variant));
+
+    quote! {
+        fn allow_missing_post_async<S: airship::types::HasAirshipState + 'static>(&self, state: S) -> airship::types::StateFuture<S, bool> {
+            match *self {
+                #(#variants)*
+            }
+        }
+    }
+}
+
 fn impl_allowed_methods(
     name: &syn::Ident,
     variants: &Punctuated<Variant, Comma>
@@ -214,6 +305,122 @@ fn impl_allowed_methods(
     }
 }
 
+fn impl_base_uri(
+    name: &syn::Ident,
+    variants: &Punctuated<Variant, Comma>
+) -> proc_macro2::TokenStream
+{
+    let callback_method = quote! {
+        base_uri
+    };
+    let trailing_args = quote! {
+        , req
+    };
+    let variants = variants
+        .iter()
+        .map(|variant| impl_webmachine_enum_variant(name, &callback_method, &trailing_args, variant));
+
+    quote! {
+        fn base_uri<S: airship::types::HasAirshipState>(&self, state: &mut S, req: &Request) -> Option<hyper::Uri> {
+            match *self {
+                #(#variants)*
+            }
+        }
+    }
+}
+
+fn impl_byte_ranges(
+    name: &syn::Ident,
+    variants: &Punctuated<Variant, Comma>
+) -> proc_macro2::TokenStream
+{
+    let callback_method = quote! {
+        byte_ranges
+    };
+    let trailing_args = quote! {
+        , req
+    };
+    let variants = variants
+        .iter()
+        .map(|variant| impl_webmachine_enum_variant(name, &callback_method, &trailing_args, variant));
+
+    quote! {
+        fn byte_ranges<S: airship::types::HasAirshipState>(&self, state: &mut S, req: &Request) -> Option<(u64, airship::resource::RangeProducer<S>)> {
+            match *self {
+                #(#variants)*
+            }
+        }
+    }
+}
+
+fn impl_cache_control(
+    name: &syn::Ident,
+    variants: &Punctuated<Variant, Comma>
+) -> proc_macro2::TokenStream
+{
+    let callback_method = quote! {
+        cache_control
+    };
+    let trailing_args = quote! {};
+    let variants = variants
+        .iter()
+        .map(|variant| impl_webmachine_enum_variant(name, &callback_method, &trailing_args, variant));
+
+    quote! {
+        fn cache_control<S: airship::types::HasAirshipState>(&self, state: &mut S) -> Vec<hyper::header::CacheDirective> {
+            match *self {
+                #(#variants)*
+            }
+        }
+    }
+}
+
+fn impl_charsets_provided(
+    name: &syn::Ident,
+    variants: &Punctuated<Variant, Comma>
+) -> proc_macro2::TokenStream
+{
+    let callback_method = quote! {
+        charsets_provided
+    };
+    let trailing_args = quote! {};
+    let variants = variants
+        .iter()
+        .map(|variant| impl_webmachine_enum_variant(name, &callback_method, &trailing_args, variant));
+
+    quote! {
+        fn charsets_provided<S: airship::types::HasAirshipState>(&self, state: &mut S) -> Vec<(hyper::header::Charset, Option<airship::resource::CharsetConverter>)> {
+            match *self {
+                #(#variants)*
+            }
+        }
+    }
+}
+
+fn impl_content_length(
+    name: &syn::Ident,
+    variants: &Punctuated<Variant, Comma>
+) -> proc_macro2::TokenStream
+{
+    let callback_method = quote! {
+        content_length
+    };
+    let trailing_args = quote! {
+        , req
+    };
+    let variants = variants
+        .iter()
+        .map(|variant| impl_webmachine_enum_variant(name, &callback_method, &trailing_args, variant));
+
+    quote! {
+        fn content_length<S: airship::types::HasAirshipState>(&self, state: &mut S, req: &Request) -> Option<u64> {
+            match *self {
+                #(#variants)*
+            }
+        }
+    }
+}
+
 fn impl_content_types_accepted(
     name: &syn::Ident,
     variants: &Punctuated<Variant, Comma>
@@ -228,7 +435,7 @@ fn impl_content_types_accepted(
         .map(|variant| impl_webmachine_enum_variant(name, &callback_method, &trailing_args, variant));
 
     quote! {
-        fn content_types_accepted<S: airship::types::HasAirshipState>(&self, state: &mut S) -> Vec<(Mime, fn(&Request))> {
+        fn content_types_accepted<S: airship::types::HasAirshipState>(&self, state: &mut S) -> Vec<(Mime, airship::resource::BodyAcceptor<S>)> {
             match *self {
                 #(#variants)*
             }
@@ -250,7 +457,7 @@ fn impl_content_types_provided(
         .map(|variant| impl_webmachine_enum_variant(name, &callback_method, &trailing_args, variant));
 
     quote! {
-        fn content_types_provided<S: airship::types::HasAirshipState>(&self, state: &mut S) -> Vec<(Mime, fn(&Request) -> Body)> {
+        fn content_types_provided<S: airship::types::HasAirshipState>(&self, state: &mut S) -> Vec<(Mime, airship::resource::BodyProducer<S>)> {
             match *self {
                 #(#variants)*
             }
@@ -280,6 +487,28 @@ fn impl_delete_completed(
     }
 }
 
+fn impl_delete_completed_async(
+    name: &syn::Ident,
+    variants: &Punctuated<Variant, Comma>
+) -> proc_macro2::TokenStream
+{
+    let callback_method = quote! {
+        delete_completed_async
+    };
+    let trailing_args = quote! {};
+    let variants = variants
+        .iter()
+        .map(|variant| impl_webmachine_enum_variant(name, &callback_method, &trailing_args, variant));
+
+    quote! {
+        fn delete_completed_async<S: airship::types::HasAirshipState + 'static>(&self, state: S) -> airship::types::StateFuture<S, bool> {
+            match *self {
+                #(#variants)*
+            }
+        }
+    }
+}
+
 fn impl_delete_resource(
     name: &syn::Ident,
     variants: &Punctuated<Variant, Comma>
@@ -304,6 +533,52 @@ fn impl_delete_resource(
     }
 }
 
+fn impl_delete_resource_async(
+    name: &syn::Ident,
+    variants: &Punctuated<Variant, Comma>
+) -> proc_macro2::TokenStream
+{
+    let callback_method = quote! {
+        delete_resource_async
+    };
+    let trailing_args = quote! {
+        , req
+    };
+    let variants = variants
+        .iter()
+        .map(|variant| impl_webmachine_enum_variant(name, &callback_method, &trailing_args, variant));
+
+    quote! {
+        fn delete_resource_async<S: airship::types::HasAirshipState + 'static>(&self, state: S, req: &Request) -> airship::types::StateFuture<S, bool> {
+            match *self {
+                #(#variants)*
+            }
+        }
+    }
+}
+
+fn impl_encodings_provided(
+    name: &syn::Ident,
+    variants: &Punctuated<Variant, Comma>
+) -> proc_macro2::TokenStream
+{
+    let callback_method = quote! {
+        encodings_provided
+    };
+    let trailing_args = quote! {};
+    let variants = variants
+        .iter()
+        .map(|variant| impl_webmachine_enum_variant(name, &callback_method, &trailing_args, variant));
+
+    quote! {
+        fn encodings_provided<S: airship::types::HasAirshipState>(&self, state: &mut S) -> Vec<hyper::header::Encoding> {
+            match *self {
+                #(#variants)*
+            }
+        }
+    }
+}
+
 fn impl_entity_too_large(
     name: &syn::Ident,
     variants: &Punctuated<Variant, Comma>
@@ -328,6 +603,28 @@ fn impl_entity_too_large(
     }
 }
 
+fn impl_expires(
+    name: &syn::Ident,
+    variants: &Punctuated<Variant, Comma>
+) -> proc_macro2::TokenStream
+{
+    let callback_method = quote! {
+        expires
+    };
+    let trailing_args = quote! {};
+    let variants = variants
+        .iter()
+        .map(|variant| impl_webmachine_enum_variant(name, &callback_method, &trailing_args, variant));
+
+    quote! {
+        fn expires<S: airship::types::HasAirshipState>(&self, state: &mut S) -> Option<hyper::header::HttpDate> {
+            match *self {
+                #(#variants)*
+            }
+        }
+    }
+}
+
 fn impl_forbidden(
     name: &syn::Ident,
     variants: &Punctuated<Variant, Comma>
@@ -352,6 +649,30 @@ fn impl_forbidden(
     }
 }
 
+fn impl_forbidden_async(
+    name: &syn::Ident,
+    variants: &Punctuated<Variant, Comma>
+) -> proc_macro2::TokenStream
+{
+    let callback_method = quote! {
+        forbidden_async
+    };
+    let trailing_args = quote! {
+        , req
+    };
+    let variants = variants
+        .iter()
+        .map(|variant| impl_webmachine_enum_variant(name, &callback_method, &trailing_args, variant));
+
+    quote! {
+        fn forbidden_async<S: airship::types::HasAirshipState + 'static>(&self, state: S, req: &Request) -> airship::types::StateFuture<S, bool> {
+            match *self {
+                #(#variants)*
+            }
+        }
+    }
+}
+
 fn impl_generate_etag(
     name: &syn::Ident,
     variants: &Punctuated<Variant, Comma>
@@ -384,13 +705,15 @@ fn impl_implemented(
     let callback_method = quote! {
         implemented
     };
-    let trailing_args = quote! {};
+    let trailing_args = quote! {
+        , req
+    };
     let variants = variants
         .iter()
         .map(|variant| impl_webmachine_enum_variant(name, &callback_method, &trailing_args, variant));
 
     quote! {
-        fn implemented<S: airship::types::HasAirshipState>(&self, state: &mut S) -> bool {
+        fn implemented<S: airship::types::HasAirshipState>(&self, state: &mut S, req: &Request) -> bool {
             match *self {
                 #(#variants)*
             }
@@ -422,6 +745,30 @@ fn impl_is_authorized(
     }
 }
 
+fn impl_is_authorized_async(
+    name: &syn::Ident,
+    variants: &Punctuated<Variant, Comma>
+) -> proc_macro2::TokenStream
+{
+    let callback_method = quote! {
+        is_authorized_async
+    };
+    let trailing_args = quote! {
+        , req
+    };
+    let variants = variants
+        .iter()
+        .map(|variant| impl_webmachine_enum_variant(name, &callback_method, &trailing_args, variant));
+
+    quote! {
+        fn is_authorized_async<S: airship::types::HasAirshipState + 'static>(&self, state: S, req: &Request) -> airship::types::StateFuture<S, bool> {
+            match *self {
+                #(#variants)*
+            }
+        }
+    }
+}
+
 fn impl_is_conflict(
     name: &syn::Ident,
     variants: &Punctuated<Variant, Comma>
@@ -444,6 +791,28 @@ fn impl_is_conflict(
     }
 }
 
+fn impl_is_conflict_async(
+    name: &syn::Ident,
+    variants: &Punctuated<Variant, Comma>
+) -> proc_macro2::TokenStream
+{
+    let callback_method = quote! {
+        is_conflict_async
+    };
+    let trailing_args = quote! {};
+    let variants = variants
+        .iter()
+        .map(|variant| impl_webmachine_enum_variant(name, &callback_method, &trailing_args, variant));
+
+    quote! {
+        fn is_conflict_async<S: airship::types::HasAirshipState + 'static>(&self, state: S) -> airship::types::StateFuture<S, bool> {
+            match *self {
+                #(#variants)*
+            }
+        }
+    }
+}
+
 fn impl_known_content_type(
     name: &syn::Ident,
     variants: &Punctuated<Variant, Comma>
@@ -468,6 +837,28 @@ fn impl_known_content_type(
     }
 }
 
+fn impl_known_methods(
+    name: &syn::Ident,
+    variants: &Punctuated<Variant, Comma>
+) -> proc_macro2::TokenStream
+{
+    let callback_method = quote! {
+        known_methods
+    };
+    let trailing_args = quote! {};
+    let variants = variants
+        .iter()
+        .map(|variant| impl_webmachine_enum_variant(name, &callback_method, &trailing_args, variant));
+
+    quote! {
+        fn known_methods<S: airship::types::HasAirshipState>(&self, state: &mut S) -> Vec<Method> {
+            match *self {
+                #(#variants)*
+            }
+        }
+    }
+}
+
 fn impl_last_modified(
     name: &syn::Ident,
     variants: &Punctuated<Variant, Comma>
@@ -490,23 +881,21 @@ fn impl_last_modified(
     }
 }
 
-fn impl_language_available(
+fn impl_languages_provided(
     name: &syn::Ident,
     variants: &Punctuated<Variant, Comma>
 ) -> proc_macro2::TokenStream
 {
     let callback_method = quote! {
-        language_available
-    };
-    let trailing_args = quote! {
-        , accept_lang_header
+        languages_provided
     };
+    let trailing_args = quote! {};
     let variants = variants
         .iter()
         .map(|variant| impl_webmachine_enum_variant(name, &callback_method, &trailing_args, variant));
 
     quote! {
-        fn language_available<H: hyper::header::Header, S: airship::types::HasAirshipState>(&self, state: &mut S, accept_lang_header: &H) -> bool {
+        fn languages_provided<S: airship::types::HasAirshipState>(&self, state: &mut S) -> Vec<hyper::header::LanguageTag> {
             match *self {
                 #(#variants)*
             }
@@ -538,6 +927,28 @@ fn impl_malformed_request(
     }
 }
 
+fn impl_max_body_size(
+    name: &syn::Ident,
+    variants: &Punctuated<Variant, Comma>
+) -> proc_macro2::TokenStream
+{
+    let callback_method = quote! {
+        max_body_size
+    };
+    let trailing_args = quote! {};
+    let variants = variants
+        .iter()
+        .map(|variant| impl_webmachine_enum_variant(name, &callback_method, &trailing_args, variant));
+
+    quote! {
+        fn max_body_size<S: airship::types::HasAirshipState>(&self, state: &mut S) -> Option<u64> {
+            match *self {
+                #(#variants)*
+            }
+        }
+    }
+}
+
 fn impl_moved_permanently(
     name: &syn::Ident,
     variants: &Punctuated<Variant, Comma>
@@ -560,6 +971,28 @@ fn impl_moved_permanently(
     }
 }
 
+fn impl_moved_permanently_async(
+    name: &syn::Ident,
+    variants: &Punctuated<Variant, Comma>
+) -> proc_macro2::TokenStream
+{
+    let callback_method = quote! {
+        moved_permanently_async
+    };
+    let trailing_args = quote! {};
+    let variants = variants
+        .iter()
+        .map(|variant| impl_webmachine_enum_variant(name, &callback_method, &trailing_args, variant));
+
+    quote! {
+        fn moved_permanently_async<S: airship::types::HasAirshipState + 'static>(&self, state: S) -> airship::types::StateFuture<S, Option<String>> {
+            match *self {
+                #(#variants)*
+            }
+        }
+    }
+}
+
 fn impl_moved_temporarily(
     name: &syn::Ident,
     variants: &Punctuated<Variant, Comma>
@@ -582,6 +1015,28 @@ fn impl_moved_temporarily(
     }
 }
 
+fn impl_moved_temporarily_async(
+    name: &syn::Ident,
+    variants: &Punctuated<Variant, Comma>
+) -> proc_macro2::TokenStream
+{
+    let callback_method = quote! {
+        moved_temporarily_async
+    };
+    let trailing_args = quote! {};
+    let variants = variants
+        .iter()
+        .map(|variant| impl_webmachine_enum_variant(name, &callback_method, &trailing_args, variant));
+
+    quote! {
+        fn moved_temporarily_async<S: airship::types::HasAirshipState + 'static>(&self, state: S) -> airship::types::StateFuture<S, Option<String>> {
+            match *self {
+                #(#variants)*
+            }
+        }
+    }
+}
+
 fn impl_multiple_choices(
     name: &syn::Ident,
     variants: &Punctuated<Variant, Comma>
@@ -618,7 +1073,7 @@ fn impl_patch_content_types_accepted(
         .map(|variant| impl_webmachine_enum_variant(name, &callback_method, &trailing_args, variant));
 
     quote! {
-        fn patch_content_types_accepted<S: airship::types::HasAirshipState>(&self, state: &mut S) -> Vec<(Mime, fn(&Request))> {
+        fn patch_content_types_accepted<S: airship::types::HasAirshipState>(&self, state: &mut S) -> Vec<(Mime, airship::resource::BodyAcceptor<S>)> {
             match *self {
                 #(#variants)*
             }
@@ -648,6 +1103,28 @@ fn impl_previously_existed(
     }
 }
 
+fn impl_previously_existed_async(
+    name: &syn::Ident,
+    variants: &Punctuated<Variant, Comma>
+) -> proc_macro2::TokenStream
+{
+    let callback_method = quote! {
+        previously_existed_async
+    };
+    let trailing_args = quote! {};
+    let variants = variants
+        .iter()
+        .map(|variant| impl_webmachine_enum_variant(name, &callback_method, &trailing_args, variant));
+
+    quote! {
+        fn previously_existed_async<S: airship::types::HasAirshipState + 'static>(&self, state: S) -> airship::types::StateFuture<S, bool> {
+            match *self {
+                #(#variants)*
+            }
+        }
+    }
+}
+
 fn impl_process_post(
     name: &syn::Ident,
     variants: &Punctuated<Variant, Comma>
@@ -664,7 +1141,31 @@ fn impl_process_post(
         .map(|variant| impl_webmachine_enum_variant(name, &callback_method, &trailing_args, variant));
 
     quote! {
-        fn process_post<S: airship::types::HasAirshipState>(&self, state: &mut S, req: &Request) -> airship::resource::PostResponse {
+        fn process_post<S: airship::types::HasAirshipState>(&self, state: &mut S, req: &Request) -> airship::resource::PostResponse<S> {
+            match *self {
+                #(#variants)*
+            }
+        }
+    }
+}
+
+fn impl_process_post_async(
+    name: &syn::Ident,
+    variants: &Punctuated<Variant, Comma>
+) -> proc_macro2::TokenStream
+{
+    let callback_method = quote! {
+        process_post_async
+    };
+    let trailing_args = quote! {
+        , req
+    };
+    let variants = variants
+        .iter()
+        .map(|variant| impl_webmachine_enum_variant(name, &callback_method, &trailing_args, variant));
+
+    quote! {
+        fn process_post_async<S: airship::types::HasAirshipState + 'static>(&self, state: S, req: &Request) -> airship::types::StateFuture<S, airship::resource::PostResponse<S>> {
             match *self {
                 #(#variants)*
             }
@@ -694,6 +1195,28 @@ fn impl_resource_exists(
     }
 }
 
+fn impl_resource_exists_async(
+    name: &syn::Ident,
+    variants: &Punctuated<Variant, Comma>
+) -> proc_macro2::TokenStream
+{
+    let callback_method = quote! {
+        resource_exists_async
+    };
+    let trailing_args = quote! {};
+    let variants = variants
+        .iter()
+        .map(|variant| impl_webmachine_enum_variant(name, &callback_method, &trailing_args, variant));
+
+    quote! {
+        fn resource_exists_async<S: airship::types::HasAirshipState + 'static>(&self, state: S) -> airship::types::StateFuture<S, bool> {
+            match *self {
+                #(#variants)*
+            }
+        }
+    }
+}
+
 fn impl_service_available(
     name: &syn::Ident,
     variants: &Punctuated<Variant, Comma>
@@ -716,6 +1239,52 @@ fn impl_service_available(
     }
 }
 
+fn impl_service_available_async(
+    name: &syn::Ident,
+    variants: &Punctuated<Variant, Comma>
+) -> proc_macro2::TokenStream
+{
+    let callback_method = quote! {
+        service_available_async
+    };
+    let trailing_args = quote! {};
+    let variants = variants
+        .iter()
+        .map(|variant| impl_webmachine_enum_variant(name, &callback_method, &trailing_args, variant));
+
+    quote! {
+        fn service_available_async<S: airship::types::HasAirshipState + 'static>(&self, state: S) -> airship::types::StateFuture<S, bool> {
+            match *self {
+                #(#variants)*
+            }
+        }
+    }
+}
+
+fn impl_upgrade(
+    name: &syn::Ident,
+    variants: &Punctuated<Variant, Comma>
+) -> proc_macro2::TokenStream
+{
+    let callback_method = quote! {
+        upgrade
+    };
+    let trailing_args = quote! {
+        , req
+    };
+    let variants = variants
+        .iter()
+        .map(|variant| impl_webmachine_enum_variant(name, &callback_method, &trailing_args, variant));
+
+    quote! {
+        fn upgrade<S: airship::types::HasAirshipState>(&self, state: &mut S, req: &Request) -> Option<airship::upgrade::Upgrade> {
+            match *self {
+                #(#variants)*
+            }
+        }
+    }
+}
+
 fn impl_uri_too_long(
     name: &syn::Ident,
     variants: &Punctuated<Variant, Comma>
@@ -763,3 +1332,25 @@ fn impl_valid_content_headers(
         }
     }
 }
+
+fn impl_variances(
+    name: &syn::Ident,
+    variants: &Punctuated<Variant, Comma>
+) -> proc_macro2::TokenStream
+{
+    let callback_method = quote! {
+        variances
+    };
+    let trailing_args = quote! {};
+    let variants = variants
+        .iter()
+        .map(|variant| impl_webmachine_enum_variant(name, &callback_method, &trailing_args, variant));
+
+    quote! {
+        fn variances<S: airship::types::HasAirshipState>(&self, state: &mut S) -> Vec<String> {
+            match *self {
+                #(#variants)*
+            }
+        }
+    }
+}